@@ -1,65 +1,101 @@
 use crate::JsonHelper;
+use anyhow::{anyhow, Result};
 use serde_json::Value;
 use std::fmt;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr}; //, ToSocketAddrs};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 
 pub(crate) struct Addr {
-    ipv4: Ipv4Addr,
+    //listen addresses resolved from http_bind/https_bind
+    addrs: Vec<SocketAddr>,
     port: u16,
-    is_active:bool,
+    is_active: bool,
     is_https: bool,
 }
 impl Addr {
-    pub fn new(config: &Value, is_https: bool) -> Self {
-        Addr {
-            ipv4: "0.0.0.0".parse().unwrap(), //Ipv4Addr::new(127, 0, 0, 1),
-            port: config[if is_https { "https_port" } else { "http_port" }].u64(80) as u16,
-            is_active: config[if is_https { "https_active" } else { "http_active" }].bool(false),
-            is_https,
+    //"http_bind"/"https_bind" is an IPv4/IPv6 address or hostname, or a list of them,
+    //default is 0.0.0.0, IPv6 may be written in brackets like [::]
+    pub fn new(config: &Value, is_https: bool) -> Result<Self> {
+        let port = config[if is_https { "https_port" } else { "http_port" }].u64(80) as u16;
+        let is_active = config[if is_https { "https_active" } else { "http_active" }].bool(false);
+        let bind_key = if is_https { "https_bind" } else { "http_bind" };
+        let binds: Vec<&str> = match &config[bind_key] {
+            Value::String(bind) => vec![bind.as_str()],
+            Value::Array(binds) => binds.iter().filter_map(|x| x.as_str()).collect(),
+            _ => vec!["0.0.0.0"],
+        };
+        let mut addrs: Vec<SocketAddr> = Vec::new();
+        if is_active {
+            if binds.is_empty() {
+                return Err(anyhow!("{} is empty", bind_key));
+            }
+            for bind in binds {
+                for addr in resolve(bind, port)? {
+                    if !addrs.contains(&addr) {
+                        addrs.push(addr);
+                    }
+                }
+            }
         }
+        Ok(Addr {
+            addrs,
+            port,
+            is_active,
+            is_https,
+        })
+    }
+    pub fn is_active(&self) -> bool {
+        self.is_active
     }
-    pub fn get(&self) -> (bool, SocketAddr) {
-        (
-            self.is_active,
-            SocketAddr::new(IpAddr::V4(self.ipv4), self.port),
-        )
+    //bind every address, IPv6 sockets are made IPv6 only when IPv4 is bound too
+    //so that [::] and 0.0.0.0 can share a port
+    #[cfg(feature = "server")]
+    pub fn listen(&self) -> Result<Vec<std::net::TcpListener>> {
+        use socket2::{Domain, Socket, Type};
+        let has_ipv4 = self.addrs.iter().any(|x| x.is_ipv4());
+        self.addrs
+            .iter()
+            .map(|addr| {
+                let listen = || -> std::io::Result<std::net::TcpListener> {
+                    let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, None)?;
+                    if addr.is_ipv6() && has_ipv4 {
+                        socket.set_only_v6(true)?;
+                    }
+                    #[cfg(unix)]
+                    socket.set_reuse_address(true)?;
+                    socket.bind(&(*addr).into())?;
+                    socket.listen(1024)?;
+                    socket.set_nonblocking(true)?;
+                    Ok(socket.into())
+                };
+                listen().map_err(|e| anyhow!("listen on {} fail: {}", addr, e))
+            })
+            .collect()
     }
-    // pub fn to_string_full(&self) -> String {
-    //     format!("{}:{}", self.ipv4, self.port)
-    // }
 }
 
-
-impl From<Addr> for SocketAddr {
-    fn from(a:Addr)->SocketAddr {
-        SocketAddr::new(IpAddr::V4(a.ipv4), a.port)
+fn resolve(bind: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    let host = bind.trim();
+    let host = host
+        .strip_prefix('[')
+        .and_then(|x| x.strip_suffix(']'))
+        .unwrap_or(host);
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    let addrs: Vec<SocketAddr> = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| anyhow!("can not resolve bind address {}: {}", bind, e))?
+        .collect();
+    if addrs.is_empty() {
+        Err(anyhow!("can not resolve bind address {}", bind))
+    } else {
+        Ok(addrs)
     }
 }
 
-// impl Into<SocketAddr> for Addr {
-//     fn into(self)->SocketAddr {
-//         SocketAddr::new(IpAddr::V4(self.ipv4), self.port)
-//     }
-// }
-
-// impl ToSocketAddrs for Addr {
-//     type Iter = std::vec::IntoIter<SocketAddr>;
-//     fn to_socket_addrs(&self) -> std::io::Result<Self::Iter> {
-//         Ok(vec![SocketAddr::new(IpAddr::V4(self.ipv4), self.port)].into_iter())
-//     }
-// }
-
 impl fmt::Display for Addr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let protocol = if self.is_https { "https" } else { "http" };
-        let ip = {
-            let ip = format!("{}", self.ipv4);
-            if ip == "0.0.0.0" {
-                "127.0.0.1".to_owned()
-            } else {
-                ip
-            }
-        };
         let port = if (self.is_https && self.port == 443_u16)
             || (!self.is_https && self.port == 80_u16)
         {
@@ -67,6 +103,40 @@ impl fmt::Display for Addr {
         } else {
             format!(":{}", self.port)
         };
-        write!(f, "{}://{}{}", protocol, ip, port)
+        let addrs = self
+            .addrs
+            .iter()
+            .map(|addr| match addr.ip() {
+                IpAddr::V4(ip) => format!("{}://{}{}", protocol, ip, port),
+                IpAddr::V6(ip) => format!("{}://[{}]{}", protocol, ip, port),
+            })
+            .collect::<Vec<String>>();
+        write!(f, "{}", addrs.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    #[test]
+    fn test_addr_bind() {
+        let config = json!({"http_active": true, "http_port": 9191});
+        let addr = Addr::new(&config, false).unwrap();
+        assert_eq!(addr.to_string(), "http://0.0.0.0:9191");
+        let config = json!({
+            "https_active": true,
+            "https_port": 443,
+            "https_bind": ["127.0.0.1", "[::]", "::1", "127.0.0.1"]
+        });
+        let addr = Addr::new(&config, true).unwrap();
+        assert_eq!(
+            addr.to_string(),
+            "https://127.0.0.1, https://[::], https://[::1]"
+        );
+        let config = json!({"http_active": true, "http_bind": "localhost"});
+        assert!(!Addr::new(&config, false).unwrap().addrs.is_empty());
+        let config = json!({"http_active": true, "http_bind": []});
+        assert!(Addr::new(&config, false).is_err());
     }
 }
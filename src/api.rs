@@ -1,36 +1,89 @@
+use crate::auth::{authorize, client_ip};
+use crate::bandwidth::ServerLimits;
 use crate::base16::{base16_decode, base16_encode};
-use crate::fileutil::{get_full_of_file, get_part_of_file};
+use crate::catalog::CatalogIndexes;
+use crate::config::served_catalog_names;
+use crate::filelist::{
+    FileEntry, FileList, CHUNK_LIST_NAME, INDEX_VERSION, LIST_NAME, PART_LIST_NAME,
+};
+use crate::fileutil::{
+    get_file_mtime, is_safe_relative_path, open_part_of_file, STREAM_BUFFER_SIZE,
+};
+use crate::tls::ClientSubject;
 use crate::AppContext;
 use crate::JsonHelper;
 
 use anyhow::anyhow;
 use axum::{
+    body::{boxed, BoxBody, StreamBody},
     extract::{ConnectInfo, Path, State},
     http::{
-        header::{HeaderMap, HeaderName, HeaderValue},
+        header::{self, HeaderMap, HeaderName, HeaderValue},
         StatusCode,
     },
+    response::{IntoResponse, Response},
     routing::get,
-    Router,
+    Extension, Router,
 };
+use futures_util::StreamExt;
 use serde_json::Value;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use tokio::{fs::File, io::Take};
+use tokio_util::io::ReaderStream;
+
+const AUTHENTICATE: &str = "Bearer realm=\"filer\"";
+
+pub(crate) struct ApiState {
+    context: Arc<AppContext>,
+    //sections listed in server.catalogs, nothing else is served
+    catalogs: Vec<String>,
+    //peers whose X-Forwarded-For gives the client address
+    trusted_proxies: Vec<String>,
+    indexes: CatalogIndexes,
+    limits: ServerLimits,
+}
 
 pub(crate) fn api(context: Arc<AppContext>) -> Router {
+    let catalogs = served_catalog_names(&context.config);
+    let limits = ServerLimits::new(context.config.clone());
+    let trusted_proxies = context.config["server"]["trusted_proxies"]
+        .as_array()
+        .map(|x| {
+            x.iter()
+                .filter_map(|x| x.as_str())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default();
+    let state = Arc::new(ApiState {
+        context,
+        catalogs,
+        trusted_proxies,
+        indexes: CatalogIndexes::new(),
+        limits,
+    });
     Router::new()
         .route("/download/:download", get(download_file))
-        .with_state(context)
+        .route("/catalog/:catalog/file/*path", get(catalog_file))
+        .with_state(state)
 }
 async fn download_file(
-    State(context): State<Arc<AppContext>>,
+    State(state): State<Arc<ApiState>>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Path(params): Path<String>,
-) -> (StatusCode, HeaderMap, Vec<u8>) {
-    use std::path::Path;
-    use tracing::info;
-    fn response_error(msg: &str) -> (StatusCode, HeaderMap, Vec<u8>) {
+    request_headers: HeaderMap,
+    client_subject: Option<Extension<ClientSubject>>,
+) -> Response {
+    use tracing::{info, warn};
+    fn response_error(status: StatusCode, msg: &str) -> (StatusCode, HeaderMap, Vec<u8>) {
         let mut headers = HeaderMap::new();
+        if status == StatusCode::UNAUTHORIZED {
+            headers.insert(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_static(AUTHENTICATE),
+            );
+        }
         headers.insert(
             HeaderName::from_static("x-body-is-error"),
             HeaderValue::from_static("yes"),
@@ -39,38 +92,78 @@ async fn download_file(
             HeaderName::from_static("content-type"),
             HeaderValue::from_static("text/plain;charset=utf-8"),
         ); //.header("content-type", "text/plain;charset=utf-8")
-        (StatusCode::NOT_ACCEPTABLE, headers, Vec::<u8>::from(msg))
+        (status, headers, Vec::<u8>::from(msg))
     }
     //debug!("params={} from {}", params, addr);
+    let ip = client_ip(addr.ip(), &request_headers, &state.trusted_proxies);
     if let Ok(params) = base16_decode(&params) {
-        let config = &context.config.clone();
-        let params: Value = serde_json::from_str(&params).unwrap();
+        let params: Value = serde_json::from_str(&params).unwrap_or(Value::Null);
         let catalog = params["catalog"].str("tcsoftV6");
-        let path = config[catalog]["path"].str("download");
         let file = params["file"].str("");
         let skip = params["skip"].u64(0);
         let take = params["take"].u64(0);
 
-        let bytes = if !file.is_empty() {
-            let file_name = String::from(path) + "/" + file;
-            if file == "filelist.txt" {
-                info!("from {:?}, download {}", addr, catalog);
+        let part = if !file.is_empty() {
+            if file == LIST_NAME {
+                info!("from {:?}, download {}", ip, catalog);
             }
-            let path = Path::new(&file_name);
-            if path.exists() {
-                if take == 0 {
-                    get_full_of_file(&file_name).await
-                } else {
-                    get_part_of_file(&file_name, skip, take).await
+            let subject = client_subject.and_then(|x| x.0 .0);
+            let resolved = resolve_catalog_file(
+                &state,
+                catalog,
+                file,
+                ip,
+                &request_headers,
+                subject.as_deref(),
+            )
+            .await;
+            match resolved {
+                //clients before list version 2 do not ask for it and panic on the header line,
+                //they get the digest,size,name csv they can read
+                Ok((file_name, _))
+                    if file == LIST_NAME && params["list_version"].u64(1) < INDEX_VERSION =>
+                {
+                    return match legacy_file_list(&file_name).await {
+                        Ok(body) => {
+                            let take = body.len() as u64;
+                            let mut headers = HeaderMap::new();
+                            headers.insert(HeaderName::from_static("x-skip"), HeaderValue::from(0));
+                            headers
+                                .insert(HeaderName::from_static("x-take"), HeaderValue::from(take));
+                            headers.insert(
+                                HeaderName::from_static("content-type"),
+                                HeaderValue::from_static("application/octet-stream"),
+                            );
+                            headers.insert(
+                                HeaderName::from_static("content-length"),
+                                HeaderValue::from(take),
+                            );
+                            (StatusCode::OK, headers, body).into_response()
+                        }
+                        Err(e) => {
+                            response_error(StatusCode::NOT_ACCEPTABLE, &format!("Error：{:?}", e))
+                                .into_response()
+                        }
+                    };
+                }
+                Ok((file_name, _)) => open_part_of_file(&file_name, skip, take).await,
+                Err((status, msg))
+                    if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN =>
+                {
+                    warn!("from {:?}, refuse {}: {}", ip, catalog, msg);
+                    return response_error(status, &format!("Error：{}", msg)).into_response();
+                }
+                Err((_, msg)) => {
+                    warn!("from {:?}, refuse {}: {}", ip, catalog, msg);
+                    Err(anyhow!(msg))
                 }
-            } else {
-                Err(anyhow!("{} does not exist", file_name))
             }
         } else {
             Err(anyhow!("file name not provided"))
         };
-        match bytes {
-            Ok((skip, take, bytes)) => {
+        match part {
+            //streamed from disk through a bounded buffer, never held whole in memory
+            Ok((skip, take, reader)) => {
                 let mut headers = HeaderMap::new();
                 headers.insert(HeaderName::from_static("x-skip"), HeaderValue::from(skip)); // .header("x-skip", skip)
                 headers.insert(HeaderName::from_static("x-take"), HeaderValue::from(take)); // .header("x-take", take)
@@ -78,15 +171,277 @@ async fn download_file(
                     HeaderName::from_static("content-type"),
                     HeaderValue::from_static("application/octet-stream"),
                 ); //.header("content-type", "application/octet-stream")
-                (StatusCode::OK, headers, bytes)
+                headers.insert(
+                    HeaderName::from_static("content-length"),
+                    HeaderValue::from(take),
+                );
+                let body = file_body(&state, catalog, ip, reader);
+                (StatusCode::OK, headers, body).into_response()
             }
-            Err(e) => response_error(&format!("Error：{:?}", e)),
+            Err(e) => response_error(StatusCode::NOT_ACCEPTABLE, &format!("Error：{:?}", e))
+                .into_response(),
         }
     } else {
-        response_error(&format!(
+        response_error(StatusCode::NOT_ACCEPTABLE, &format!(
             "Error：download file fail, expect base16 encoded string as param,for example: {}, but get param: {} ",
             base16_encode(r#"{"catalog":"tcsoftV6","file":"filelist.txt"}"#).unwrap(),
             params
         ))
+        .into_response()
+    }
+}
+
+//plain http access to a catalog file for curl, wget, browsers and caching proxies,
+//honors a single Range, ETag is the digest from the catalog's filelist.txt
+async fn catalog_file(
+    State(state): State<Arc<ApiState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((catalog, file)): Path<(String, String)>,
+    request_headers: HeaderMap,
+    client_subject: Option<Extension<ClientSubject>>,
+) -> Response {
+    use tracing::warn;
+    fn response_status(status: StatusCode, msg: &str) -> Response {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain;charset=utf-8"),
+        );
+        if status == StatusCode::UNAUTHORIZED {
+            headers.insert(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_static(AUTHENTICATE),
+            );
+        }
+        (status, headers, msg.to_string()).into_response()
+    }
+    let file = file.trim_start_matches('/');
+    let ip = client_ip(addr.ip(), &request_headers, &state.trusted_proxies);
+    let subject = client_subject.and_then(|x| x.0 .0);
+    let resolved = resolve_catalog_file(
+        &state,
+        &catalog,
+        file,
+        ip,
+        &request_headers,
+        subject.as_deref(),
+    )
+    .await;
+    let (file_name, entry) = match resolved {
+        Ok(resolved) => resolved,
+        Err((status, msg)) => {
+            warn!("from {:?}, refuse {}: {}", ip, catalog, msg);
+            return response_status(status, &msg);
+        }
+    };
+    let meta = match tokio::fs::metadata(&file_name).await {
+        Ok(meta) if meta.is_file() => meta,
+        _ => return response_status(StatusCode::NOT_FOUND, &format!("{} not found", file)),
+    };
+    let size = meta.len();
+    //a file changed since the last index has no trustworthy digest
+    let etag = entry
+        .filter(|x| x.size == size && (x.mtime == 0 || x.mtime == get_file_mtime(&meta)))
+        .map(|x| format!("\"{}\"", x.digest));
+    let request_header = |name: header::HeaderName| {
+        request_headers
+            .get(name)
+            .and_then(|x| x.to_str().ok())
+            .map(String::from)
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    if let Some(etag) = etag.as_deref() {
+        if let Ok(value) = HeaderValue::from_str(etag) {
+            headers.insert(header::ETAG, value);
+        }
+        if let Some(if_none_match) = request_header(header::IF_NONE_MATCH) {
+            if if_none_match
+                .split(',')
+                .any(|x| x.trim() == etag || x.trim() == "*")
+            {
+                return (StatusCode::NOT_MODIFIED, headers).into_response();
+            }
+        }
+    }
+    //If-Range with another version asks for the full file
+    let range = request_header(header::RANGE).filter(|_| {
+        request_header(header::IF_RANGE)
+            .map(|x| Some(x.as_str()) == etag.as_deref())
+            .unwrap_or(true)
+    });
+    let (status, skip, take) = match range.map(|x| parse_range(&x, size)) {
+        Some(Some(Some((start, end)))) => {
+            let content_range = format!("bytes {}-{}/{}", start, end, size);
+            if let Ok(value) = HeaderValue::from_str(&content_range) {
+                headers.insert(header::CONTENT_RANGE, value);
+            }
+            (StatusCode::PARTIAL_CONTENT, start, end - start + 1)
+        }
+        Some(Some(None)) => {
+            let content_range = format!("bytes */{}", size);
+            if let Ok(value) = HeaderValue::from_str(&content_range) {
+                headers.insert(header::CONTENT_RANGE, value);
+            }
+            return (StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response();
+        }
+        _ => (StatusCode::OK, 0, size),
+    };
+    match open_part_of_file(&file_name, skip, take).await {
+        Ok((_, take, reader)) => {
+            headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/octet-stream"),
+            );
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(take));
+            let body = file_body(&state, &catalog, ip, reader);
+            (status, headers, body).into_response()
+        }
+        Err(e) => response_status(StatusCode::INTERNAL_SERVER_ERROR, &format!("{:?}", e)),
+    }
+}
+
+//filelist.txt as digest,size,name lines for clients before 1.0.10
+async fn legacy_file_list(file_name: &str) -> anyhow::Result<Vec<u8>> {
+    let str = tokio::fs::read_to_string(file_name).await?;
+    Ok(FileList::parse(&str)?.to_legacy_string().into_bytes())
+}
+
+//streamed from disk through a bounded buffer, each chunk paced by the bandwidth limits
+//of the server and the catalog
+fn file_body(state: &Arc<ApiState>, catalog: &str, ip: IpAddr, reader: Take<File>) -> BoxBody {
+    let state = state.clone();
+    let catalog = catalog.to_string();
+    let stream = ReaderStream::with_capacity(reader, STREAM_BUFFER_SIZE).then(move |chunk| {
+        let state = state.clone();
+        let catalog = catalog.clone();
+        async move {
+            if let Ok(bytes) = &chunk {
+                state
+                    .limits
+                    .throttle(&catalog, ip, bytes.len() as u64)
+                    .await;
+            }
+            chunk
+        }
+    });
+    boxed(StreamBody::new(stream))
+}
+
+//map file onto a file inside the root of a served catalog and listed in its filelist.txt,
+//after the client passed the access rules of the catalog,
+//return (file_name,entry) where entry is None for filelist.txt and its part and chunk lists,
+//or (status,reason) of the refusal
+async fn resolve_catalog_file(
+    state: &ApiState,
+    catalog: &str,
+    file: &str,
+    ip: IpAddr,
+    headers: &HeaderMap,
+    subject: Option<&[(String, String)]>,
+) -> Result<(String, Option<FileEntry>), (StatusCode, String)> {
+    use tokio::fs::canonicalize;
+    if !state.catalogs.iter().any(|x| x == catalog) {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("catalog {} is not served", catalog),
+        ));
+    }
+    authorize(&state.context.config[catalog], ip, headers, subject)?;
+    if !is_safe_relative_path(file) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("invalid file name {}", file),
+        ));
+    }
+    let path = state.context.config[catalog]["path"].str("");
+    let entry = if file == LIST_NAME || file == PART_LIST_NAME || file == CHUNK_LIST_NAME {
+        None
+    } else {
+        let index = state
+            .indexes
+            .get(catalog, path)
+            .await
+            .map_err(|e| (StatusCode::NOT_FOUND, format!("{:?}", e)))?;
+        match index.get(file) {
+            Some(entry) => Some(entry.clone()),
+            None => {
+                return Err((
+                    StatusCode::NOT_FOUND,
+                    format!("{} is not in the index of {}", file, catalog),
+                ))
+            }
+        }
+    };
+    //symbolic links must not lead out of the catalog
+    let not_found = |e: std::io::Error| (StatusCode::NOT_FOUND, format!("{}: {}", file, e));
+    let root = canonicalize(path).await.map_err(not_found)?;
+    let file_name = canonicalize(String::from(path) + "/" + file)
+        .await
+        .map_err(not_found)?;
+    if !file_name.starts_with(&root) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            format!("{} is outside of catalog {}", file, catalog),
+        ));
+    }
+    let file_name = file_name
+        .to_str()
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("{} is not utf-8", file)))?;
+    Ok((file_name.to_string(), entry))
+}
+
+//parse a single range of Range: bytes=start-end, bytes=start- or bytes=-suffix
+//return None to ignore a malformed or multiple range and serve the full file,
+//Some(None) when the range is not satisfiable, otherwise Some(Some((start,end))) inclusive
+fn parse_range(range: &str, size: u64) -> Option<Option<(u64, u64)>> {
+    let range = range.trim().strip_prefix("bytes=")?;
+    if range.contains(',') {
+        return None;
+    }
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    let (start, end) = if start.is_empty() {
+        let suffix = end.parse::<u64>().ok()?;
+        if suffix == 0 {
+            return Some(None);
+        }
+        (size.saturating_sub(suffix), size.saturating_sub(1))
+    } else {
+        let start = start.parse::<u64>().ok()?;
+        let end = if end.is_empty() {
+            size.saturating_sub(1)
+        } else {
+            let end = end.parse::<u64>().ok()?;
+            if end < start {
+                return None;
+            }
+            end.min(size.saturating_sub(1))
+        };
+        (start, end)
+    };
+    if start >= size {
+        Some(None)
+    } else {
+        Some(Some((start, end)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(Some((0, 99))));
+        assert_eq!(parse_range("bytes=900-", 1000), Some(Some((900, 999))));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(Some((900, 999))));
+        assert_eq!(parse_range("bytes=500-2000", 1000), Some(Some((500, 999))));
+        assert_eq!(parse_range("bytes=-2000", 1000), Some(Some((0, 999))));
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(None));
+        assert_eq!(parse_range("bytes=0-", 0), Some(None));
+        assert_eq!(parse_range("bytes=0-1,5-9", 1000), None);
+        assert_eq!(parse_range("bytes=9-1", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
     }
 }
@@ -0,0 +1,296 @@
+use crate::tls::subject_to_string;
+use crate::JsonHelper;
+use axum::http::{header, HeaderMap, StatusCode};
+use serde_json::Value;
+use std::net::{IpAddr, Ipv4Addr};
+
+//address given to peers on the unix socket, no tcp peer has it, only the rule "unix" allows it
+pub(crate) const UNIX_PEER: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+
+//access rules of a catalog section:
+//"allow_ips": ["192.168.1.0/24", "10.0.0.5", "fd00::/8", "unix"], missing means any address,
+//"unix" allows peers on the unix socket
+//"tokens": ["token"] and "users": {"name": "password"}, both missing means no credentials needed
+//credentials come as Authorization: Bearer token or Bearer name:password
+//"client_subjects": ["shop-01", "C=CN,O=Acme,CN=shop-02"] requires a https client certificate
+//whose common name or whole subject is listed, "," or "=" in a value is escaped as "\,"
+pub(crate) fn authorize(
+    catalog_config: &Value,
+    ip: IpAddr,
+    headers: &HeaderMap,
+    subject: Option<&[(String, String)]>,
+) -> Result<(), (StatusCode, String)> {
+    if let Some(client_subjects) = catalog_config["client_subjects"].as_array() {
+        let subject = subject.ok_or_else(|| {
+            (
+                StatusCode::FORBIDDEN,
+                "client certificate required".to_string(),
+            )
+        })?;
+        if !client_subjects
+            .iter()
+            .filter_map(|x| x.as_str())
+            .any(|x| subject_allowed(x, subject))
+        {
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!(
+                    "client certificate {} is not allowed",
+                    subject_to_string(subject)
+                ),
+            ));
+        }
+    }
+    if let Some(allow_ips) = catalog_config["allow_ips"].as_array() {
+        if !allow_ips
+            .iter()
+            .filter_map(|x| x.as_str())
+            .any(|x| ip_allowed(x, ip))
+        {
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!("address {} is not allowed", ip),
+            ));
+        }
+    }
+    let tokens = catalog_config["tokens"].as_array();
+    let users = catalog_config["users"].as_object();
+    if tokens.is_none() && users.is_none() {
+        return Ok(());
+    }
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "))
+        .map(|x| x.trim());
+    let bearer = match bearer {
+        Some(bearer) if !bearer.is_empty() => bearer,
+        _ => return Err((StatusCode::UNAUTHORIZED, "credentials required".to_string())),
+    };
+    let token_matched = tokens
+        .map(|tokens| {
+            tokens
+                .iter()
+                .filter_map(|x| x.as_str())
+                .any(|x| constant_time_eq(x, bearer))
+        })
+        .unwrap_or(false);
+    let user_matched = match (users, bearer.split_once(':')) {
+        (Some(users), Some((name, password))) => users
+            .get(name)
+            .map(|x| constant_time_eq(x.str(""), password) && !password.is_empty())
+            .unwrap_or(false),
+        _ => false,
+    };
+    if token_matched || user_matched {
+        Ok(())
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "invalid credentials".to_string()))
+    }
+}
+
+//rule is a whole subject or the common name in it, compared attribute by attribute
+fn subject_allowed(rule: &str, subject: &[(String, String)]) -> bool {
+    match parse_subject(rule) {
+        Some(rule) => rule == subject,
+        None => subject
+            .iter()
+            .any(|(name, value)| name == "CN" && value == rule),
+    }
+}
+
+//(type,value) pairs of a subject written like C=CN,O=Acme,CN=shop-02 with "\" escaping the
+//next character, None for a plain common name without "="
+fn parse_subject(rule: &str) -> Option<Vec<(String, String)>> {
+    let mut attributes = Vec::new();
+    let mut name: Option<String> = None;
+    let mut value = String::new();
+    let mut chars = rule.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => value.extend(chars.next()),
+            '=' if name.is_none() => name = Some(std::mem::take(&mut value).trim().to_string()),
+            ',' => attributes.push((name.take()?, std::mem::take(&mut value))),
+            c => value.push(c),
+        }
+    }
+    attributes.push((name?, value));
+    Some(attributes)
+}
+
+//address of the client, taken from the last X-Forwarded-For address not itself a trusted proxy
+//when the peer is one of "trusted_proxies" in server, like ["unix", "127.0.0.1"]
+pub(crate) fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[String]) -> IpAddr {
+    let trusted = |ip: IpAddr| trusted_proxies.iter().any(|x| ip_allowed(x, ip));
+    if !trusted(peer) {
+        return peer;
+    }
+    let forwarded = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .map(|x| x.trim().parse::<IpAddr>().ok())
+        .collect::<Vec<Option<IpAddr>>>();
+    let mut client = peer;
+    for ip in forwarded.into_iter().rev() {
+        match ip {
+            Some(ip) if ip != UNIX_PEER => {
+                client = ip;
+                if !trusted(ip) {
+                    break;
+                }
+            }
+            //an entry that is not an address can not be traced further
+            _ => break,
+        }
+    }
+    client
+}
+
+//rule is an address or a cidr block like 192.168.1.0/24, or "unix" for the unix socket
+fn ip_allowed(rule: &str, ip: IpAddr) -> bool {
+    if ip == UNIX_PEER || rule.trim() == "unix" {
+        return ip == UNIX_PEER && rule.trim() == "unix";
+    }
+    let (network, prefix) = match rule.trim().split_once('/') {
+        Some((network, prefix)) => (network, prefix.parse::<u32>().ok()),
+        None => (rule.trim(), None),
+    };
+    let network = match network.parse::<IpAddr>() {
+        Ok(network) => network,
+        Err(_) => return false,
+    };
+    match (network, ip.to_canonical()) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let prefix = prefix.unwrap_or(32).min(32);
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(network) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let prefix = prefix.unwrap_or(128).min(128);
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(network) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+//compare secrets without leaking how many leading bytes match
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+    use serde_json::json;
+    #[test]
+    fn test_ip_allowed() {
+        let ip: IpAddr = "192.168.1.20".parse().unwrap();
+        assert!(ip_allowed("192.168.1.0/24", ip));
+        assert!(ip_allowed("192.168.1.20", ip));
+        assert!(ip_allowed("0.0.0.0/0", ip));
+        assert!(!ip_allowed("192.168.2.0/24", ip));
+        assert!(!ip_allowed("fd00::/8", ip));
+        assert!(ip_allowed(
+            "192.168.1.0/24",
+            "::ffff:192.168.1.20".parse().unwrap()
+        ));
+        assert!(ip_allowed("fd00::/8", "fd12::1".parse().unwrap()));
+        assert!(!ip_allowed("bad", ip));
+        assert!(!ip_allowed("unix", ip));
+        assert!(ip_allowed("unix", UNIX_PEER));
+        assert!(!ip_allowed("127.0.0.1", UNIX_PEER));
+        assert!(!ip_allowed("0.0.0.0/0", UNIX_PEER));
+    }
+    #[test]
+    fn test_client_ip() {
+        let trusted = vec!["unix".to_string(), "10.0.0.1".to_string()];
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("1.2.3.4, 5.6.7.8, 10.0.0.1"),
+        );
+        //the proxy appends its peer, addresses before it are written by the client
+        assert_eq!(
+            client_ip(UNIX_PEER, &headers, &trusted),
+            "5.6.7.8".parse::<IpAddr>().unwrap()
+        );
+        let peer: IpAddr = "9.9.9.9".parse().unwrap();
+        assert_eq!(client_ip(peer, &headers, &trusted), peer);
+        assert_eq!(client_ip(UNIX_PEER, &HeaderMap::new(), &trusted), UNIX_PEER);
+        headers.insert("x-forwarded-for", HeaderValue::from_static("1.2.3.4, bad"));
+        assert_eq!(client_ip(UNIX_PEER, &headers, &trusted), UNIX_PEER);
+    }
+    #[test]
+    fn test_authorize_subject() {
+        let config = json!({"client_subjects": ["shop-01", "O=Acme,CN=shop-02", "O=a\\,b,CN=c"]});
+        let ip: IpAddr = "10.1.2.3".parse().unwrap();
+        let headers = HeaderMap::new();
+        let subject = |x: &[(&str, &str)]| -> Vec<(String, String)> {
+            x.iter().map(|x| (x.0.into(), x.1.into())).collect()
+        };
+        let allowed = |x: &[(&str, &str)]| authorize(&config, ip, &headers, Some(&subject(x)));
+        assert!(allowed(&[("O", "Acme"), ("CN", "shop-01")]).is_ok());
+        assert!(allowed(&[("O", "Acme"), ("CN", "shop-02")]).is_ok());
+        assert!(allowed(&[("O", "Other"), ("CN", "shop-02")]).is_err());
+        assert!(allowed(&[("O", "a,b"), ("CN", "c")]).is_ok());
+        //a common name smuggled into another attribute is no common name
+        assert!(allowed(&[("O", "x,CN=shop-01"), ("CN", "evil")]).is_err());
+        assert!(allowed(&[("O", "Acme,CN=shop-02")]).is_err());
+        assert_eq!(
+            authorize(&config, ip, &headers, None).err().map(|x| x.0),
+            Some(StatusCode::FORBIDDEN)
+        );
+    }
+    #[test]
+    fn test_authorize() {
+        let config = json!({
+            "allow_ips": ["10.0.0.0/8"],
+            "tokens": ["s3cret"],
+            "users": {"alice": "pw"}
+        });
+        let ip: IpAddr = "10.1.2.3".parse().unwrap();
+        let bearer = |x: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::AUTHORIZATION, HeaderValue::from_str(x).unwrap());
+            headers
+        };
+        let status = |x: Result<(), (StatusCode, String)>| x.err().map(|x| x.0);
+        assert_eq!(
+            status(authorize(&config, ip, &bearer("Bearer s3cret"), None)),
+            None
+        );
+        assert_eq!(
+            status(authorize(&config, ip, &bearer("Bearer alice:pw"), None)),
+            None
+        );
+        assert_eq!(
+            status(authorize(&config, ip, &HeaderMap::new(), None)),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            status(authorize(&config, ip, &bearer("Bearer alice:x"), None)),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            status(authorize(
+                &config,
+                "11.0.0.1".parse().unwrap(),
+                &bearer("Bearer s3cret"),
+                None
+            )),
+            Some(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            status(authorize(&json!({}), ip, &HeaderMap::new(), None)),
+            None
+        );
+    }
+}
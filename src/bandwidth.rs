@@ -0,0 +1,213 @@
+use serde_json::Value;
+#[cfg(feature = "server")]
+use std::collections::HashMap;
+#[cfg(feature = "server")]
+use std::net::IpAddr;
+#[cfg(feature = "download")]
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+//("server" or catalog,client address or None for all clients)
+#[cfg(feature = "server")]
+type BucketKey = (String, Option<IpAddr>);
+
+//bytes per second from 10485760, "10MB" or "80MiB", missing or 0 means unlimited
+pub(crate) fn parse_rate(value: &Value) -> Option<u64> {
+    let rate = match value {
+        Value::Number(x) => x.as_u64(),
+        Value::String(x) => byte_unit::Byte::from_str(x)
+            .ok()
+            .map(|x| x.get_bytes() as u64),
+        _ => None,
+    };
+    rate.filter(|x| *x > 0)
+}
+
+//token bucket with a burst of one second, a chunk larger than the tokens left
+//borrows from the coming ones and the caller waits until they are paid back
+pub(crate) struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    pub fn new() -> Self {
+        Self {
+            tokens: f64::INFINITY,
+            updated: Instant::now(),
+        }
+    }
+
+    //take n bytes at rate bytes per second, return how long to wait before sending them
+    pub fn take(&mut self, n: u64, rate: u64) -> Duration {
+        let now = Instant::now();
+        let rate = rate as f64;
+        let refill = now.duration_since(self.updated).as_secs_f64() * rate;
+        self.tokens = (self.tokens + refill).min(rate) - n as f64;
+        self.updated = now;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+
+    #[cfg(feature = "server")]
+    pub fn idle_for(&self) -> Duration {
+        self.updated.elapsed()
+    }
+}
+
+//"max_bandwidth" of the client section, one bucket shared by every part in flight
+#[cfg(feature = "download")]
+#[derive(Clone)]
+pub(crate) struct ClientLimit {
+    rate: u64,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[cfg(feature = "download")]
+impl ClientLimit {
+    pub fn new(config: &Value) -> Option<Self> {
+        parse_rate(&config["max_bandwidth"]).map(|rate| Self {
+            rate,
+            bucket: Arc::new(Mutex::new(Bucket::new())),
+        })
+    }
+
+    pub async fn throttle(&self, n: u64) {
+        let wait = self.bucket.lock().unwrap().take(n, self.rate);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+//(max_bandwidth,max_bandwidth_per_ip) of a server or catalog section at minute of the day,
+//an entry of "bandwidth_schedule" like {"from": "08:00", "to": "18:00", "max_bandwidth": "5MB"}
+//covering that minute overrides the limits it sets, "from" later than "to" spans midnight
+pub(crate) fn rates_at(section: &Value, minute: u32) -> (Option<u64>, Option<u64>) {
+    let mut global = &section["max_bandwidth"];
+    let mut per_ip = &section["max_bandwidth_per_ip"];
+    let schedule = section["bandwidth_schedule"].as_array();
+    if let Some(entry) = schedule.and_then(|x| x.iter().find(|x| in_period(x, minute))) {
+        if !entry["max_bandwidth"].is_null() {
+            global = &entry["max_bandwidth"];
+        }
+        if !entry["max_bandwidth_per_ip"].is_null() {
+            per_ip = &entry["max_bandwidth_per_ip"];
+        }
+    }
+    (parse_rate(global), parse_rate(per_ip))
+}
+
+#[cfg(feature = "server")]
+fn in_period(entry: &Value, minute: u32) -> bool {
+    fn parse_minute(time: &str) -> Option<u32> {
+        let (hour, minute) = time.trim().split_once(':')?;
+        let (hour, minute) = (hour.parse::<u32>().ok()?, minute.parse::<u32>().ok()?);
+        (hour <= 24 && minute < 60).then_some(hour * 60 + minute)
+    }
+    match (
+        entry["from"].as_str().and_then(parse_minute),
+        entry["to"].as_str().and_then(parse_minute),
+    ) {
+        (Some(from), Some(to)) if from <= to => from <= minute && minute < to,
+        (Some(from), Some(to)) => from <= minute || minute < to,
+        _ => false,
+    }
+}
+
+#[cfg(feature = "server")]
+//bandwidth limits of the server section and of each catalog, shared by every response body,
+//a chunk waits for the global bucket and the client address bucket of both scopes
+pub(crate) struct ServerLimits {
+    config: Value,
+    buckets: Mutex<HashMap<BucketKey, Bucket>>,
+}
+
+#[cfg(feature = "server")]
+impl ServerLimits {
+    pub fn new(config: Value) -> Self {
+        Self {
+            config,
+            buckets: Default::default(),
+        }
+    }
+
+    pub async fn throttle(&self, catalog: &str, ip: IpAddr, n: u64) {
+        use chrono::Timelike;
+        let now = chrono::Local::now();
+        let minute = now.hour() * 60 + now.minute();
+        let ip = ip.to_canonical();
+        let mut wait = Duration::ZERO;
+        {
+            let mut buckets = self.buckets.lock().unwrap();
+            for scope in ["server", catalog] {
+                let (global, per_ip) = rates_at(&self.config[scope], minute);
+                for (key, rate) in [(None, global), (Some(ip), per_ip)] {
+                    if let Some(rate) = rate {
+                        let bucket = buckets
+                            .entry((scope.to_string(), key))
+                            .or_insert_with(Bucket::new);
+                        wait = wait.max(bucket.take(n, rate));
+                    }
+                }
+            }
+            //a bucket idle for a second is full again, same as a new one
+            if buckets.len() > 1024 {
+                buckets.retain(|_, x| x.idle_for() < Duration::from_secs(1));
+            }
+        }
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate(&json!(1000)), Some(1000));
+        assert_eq!(parse_rate(&json!("10MB")), Some(10_000_000));
+        assert_eq!(parse_rate(&json!("1MiB")), Some(1 << 20));
+        assert_eq!(parse_rate(&json!(0)), None);
+        assert_eq!(parse_rate(&json!("fast")), None);
+        assert_eq!(parse_rate(&Value::Null), None);
+    }
+    #[test]
+    fn test_bucket() {
+        let mut bucket = Bucket::new();
+        assert_eq!(bucket.take(1000, 1000), Duration::ZERO);
+        let wait = bucket.take(500, 1000);
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+    }
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_rates_at() {
+        let section = json!({
+            "max_bandwidth": "10MB",
+            "max_bandwidth_per_ip": "2MB",
+            "bandwidth_schedule": [
+                {"from": "08:00", "to": "18:00", "max_bandwidth": "5MB"},
+                {"from": "22:00", "to": "06:00", "max_bandwidth": 0, "max_bandwidth_per_ip": 0}
+            ]
+        });
+        assert_eq!(
+            rates_at(&section, 9 * 60),
+            (Some(5_000_000), Some(2_000_000))
+        );
+        assert_eq!(
+            rates_at(&section, 19 * 60),
+            (Some(10_000_000), Some(2_000_000))
+        );
+        assert_eq!(rates_at(&section, 23 * 60), (None, None));
+        assert_eq!(rates_at(&section, 60), (None, None));
+        assert_eq!(rates_at(&json!({}), 60), (None, None));
+    }
+}
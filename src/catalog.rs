@@ -0,0 +1,51 @@
+use crate::filelist::{FileEntry, FileList, LIST_NAME};
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::fs;
+
+//file name => entry of a catalog's filelist.txt
+pub(crate) type CatalogIndex = HashMap<String, FileEntry>;
+//(modified,size,index) of the filelist.txt an index was parsed from
+type CachedIndex = (SystemTime, u64, Arc<CatalogIndex>);
+
+//filelist.txt of each served catalog, parsed once and reloaded when it changes on disk
+#[derive(Default)]
+pub(crate) struct CatalogIndexes {
+    cache: Mutex<HashMap<String, CachedIndex>>,
+}
+
+impl CatalogIndexes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn get(&self, catalog: &str, path: &str) -> Result<Arc<CatalogIndex>> {
+        let list_file_name = String::from(path) + "/" + LIST_NAME;
+        let meta = fs::metadata(&list_file_name)
+            .await
+            .map_err(|e| anyhow!("{} of {}: {}", list_file_name, catalog, e))?;
+        let modified = meta.modified()?;
+        if let Some((cached_modified, cached_size, index)) = self.cache.lock().unwrap().get(catalog)
+        {
+            if *cached_modified == modified && *cached_size == meta.len() {
+                return Ok(index.clone());
+            }
+        }
+        let list = fs::read_to_string(&list_file_name).await?;
+        let list = FileList::parse(&list)
+            .map_err(|e| anyhow!("{} of {} is invalid: {}", list_file_name, catalog, e))?;
+        let index: Arc<CatalogIndex> = Arc::new(
+            list.entries
+                .into_iter()
+                .map(|x| (x.name.clone(), x))
+                .collect(),
+        );
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(catalog.to_string(), (modified, meta.len(), index.clone()));
+        Ok(index)
+    }
+}
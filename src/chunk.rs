@@ -0,0 +1,183 @@
+use crate::filelist::Chunks;
+#[cfg(feature = "download")]
+use anyhow::Result;
+#[cfg(feature = "download")]
+use std::collections::{HashMap, HashSet};
+
+//content-defined chunks of a large file, a cut falls where the gear hash of the last 64 bytes
+//matches the mask, so an edit only moves the boundaries around it and the other chunks of a
+//changed file keep their hashes
+pub const CHUNK_MIN_SIZE: u64 = 256 * 1024;
+pub const CHUNK_AVG_SIZE: u64 = 1024 * 1024;
+pub const CHUNK_MAX_SIZE: u64 = 4 * 1024 * 1024;
+//recorded in filelist.chunks, a list cut with other sizes is of no use
+pub const CHUNKING: [u64; 3] = [CHUNK_MIN_SIZE, CHUNK_AVG_SIZE, CHUNK_MAX_SIZE];
+
+//top bits of the gear hash, a cut is expected once in CHUNK_AVG_SIZE bytes after CHUNK_MIN_SIZE
+const MASK: u64 = !(u64::MAX >> CHUNK_AVG_SIZE.trailing_zeros());
+
+//pseudo random value of each byte from splitmix64, fixed so that server and client agree
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut seed: u64 = 0x6669_6c65_722d_6364;
+    let mut i = 0;
+    while i < 256 {
+        seed = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = seed;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+//split a stream into chunks as it is read, fed by buffers of any size
+pub(crate) struct Chunker {
+    gear: u64,
+    len: u64,
+    hasher: blake3::Hasher,
+    chunks: Chunks,
+}
+
+impl Chunker {
+    pub fn new() -> Self {
+        Self {
+            gear: 0,
+            len: 0,
+            hasher: blake3::Hasher::new(),
+            chunks: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let mut start = 0;
+        for (i, byte) in data.iter().enumerate() {
+            self.gear = (self.gear << 1).wrapping_add(GEAR[*byte as usize]);
+            self.len += 1;
+            if (self.len >= CHUNK_MIN_SIZE && self.gear & MASK == 0) || self.len >= CHUNK_MAX_SIZE {
+                self.hasher.update(&data[start..=i]);
+                self.cut();
+                start = i + 1;
+            }
+        }
+        self.hasher.update(&data[start..]);
+    }
+
+    fn cut(&mut self) {
+        self.chunks
+            .push((self.len, format!("{}", self.hasher.finalize().to_hex())));
+        self.hasher.reset();
+        self.gear = 0;
+        self.len = 0;
+    }
+
+    pub fn finish(mut self) -> Chunks {
+        if self.len > 0 {
+            self.cut();
+        }
+        self.chunks
+    }
+}
+
+//chunks of a local file read through a bounded buffer
+#[cfg(feature = "download")]
+pub async fn chunk_file(file_name: &str) -> Result<Chunks> {
+    use crate::fileutil::STREAM_BUFFER_SIZE;
+    use tokio::io::AsyncReadExt;
+    let mut source = tokio::fs::File::open(file_name).await?;
+    let mut chunker = Chunker::new();
+    let mut buffer = vec![0u8; STREAM_BUFFER_SIZE];
+    loop {
+        let n = source.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        chunker.update(&buffer[..n]);
+    }
+    Ok(chunker.finish())
+}
+
+//where the chunks of local files are, so that a chunk found anywhere in the client's tree is
+//copied instead of fetched, the content may have changed since, a copy is checked by its hash
+#[cfg(feature = "download")]
+#[derive(Default)]
+pub(crate) struct ChunkIndex {
+    //chunk hash => (file_name,offset,size) of its first occurrence
+    chunks: HashMap<String, (String, u64, u64)>,
+    files: HashSet<String>,
+}
+
+#[cfg(feature = "download")]
+impl ChunkIndex {
+    pub fn add(&mut self, file_name: &str, chunks: &Chunks) {
+        let mut offset = 0;
+        for (size, hash) in chunks {
+            self.chunks
+                .entry(hash.clone())
+                .or_insert_with(|| (file_name.to_string(), offset, *size));
+            offset += size;
+        }
+        self.files.insert(file_name.to_string());
+    }
+
+    pub fn contains_file(&self, file_name: &str) -> bool {
+        self.files.contains(file_name)
+    }
+
+    //(file_name,offset) of a chunk of hash and size
+    pub fn get(&self, hash: &str, size: u64) -> Option<(String, u64)> {
+        self.chunks
+            .get(hash)
+            .filter(|x| x.2 == size)
+            .map(|x| (x.0.clone(), x.1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn chunks_of(data: &[u8], buffer_size: usize) -> Chunks {
+        let mut chunker = Chunker::new();
+        data.chunks(buffer_size).for_each(|x| chunker.update(x));
+        chunker.finish()
+    }
+    #[test]
+    fn test_chunker() {
+        let mut seed: u32 = 1;
+        let data: Vec<u8> = (0..12 * 1024 * 1024)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        let chunks = chunks_of(&data, 256 * 1024);
+        assert_eq!(chunks.iter().map(|x| x.0).sum::<u64>(), data.len() as u64);
+        assert!(chunks.len() > 2);
+        assert!(chunks[..chunks.len() - 1]
+            .iter()
+            .all(|x| x.0 >= CHUNK_MIN_SIZE && x.0 <= CHUNK_MAX_SIZE));
+        //boundaries do not depend on how the stream is read
+        assert_eq!(chunks_of(&data, 1000), chunks);
+        //a few bytes inserted near the start leave the later chunks alone
+        let mut edited = data[..1000].to_vec();
+        edited.extend_from_slice(b"inserted");
+        edited.extend_from_slice(&data[1000..]);
+        let edited = chunks_of(&edited, 256 * 1024);
+        let mut index = ChunkIndex::default();
+        index.add("old", &chunks);
+        let reused = edited
+            .iter()
+            .filter(|x| index.get(&x.1, x.0).is_some())
+            .count();
+        assert!(reused >= chunks.len() - 2);
+        assert!(index.contains_file("old") && !index.contains_file("new"));
+        assert_eq!(
+            index.get(&chunks[1].1, chunks[1].0),
+            Some(("old".into(), chunks[0].0))
+        );
+        assert_eq!(index.get(&chunks[1].1, 1), None);
+    }
+}
@@ -0,0 +1,143 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+//throughput and failures are judged once per window
+const WINDOW: Duration = Duration::from_secs(2);
+
+//limit of parts fetched from the server at once, fixed at max_tasks or adapted to the link:
+//each window the limit grows by one while every slot was busy and no part failed,
+//it is halved after a failure or when throughput of a busy window drops by a fifth
+pub(crate) struct Concurrency {
+    state: Mutex<State>,
+    notify: Notify,
+}
+
+struct State {
+    limit: u64,
+    max: u64,
+    adaptive: bool,
+    in_flight: u64,
+    //every slot was taken at some point of the window
+    saturated: bool,
+    window_start: Instant,
+    window_bytes: u64,
+    window_errors: u64,
+    last_rate: f64,
+}
+
+//a slot of a part in flight, given back on drop
+pub(crate) struct Permit<'a>(&'a Concurrency);
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().in_flight -= 1;
+        self.0.notify.notify_waiters();
+    }
+}
+
+impl Concurrency {
+    //adaptive starts low and climbs, a fixed limit is max from the start
+    pub fn new(max: u64, adaptive: bool) -> Self {
+        let max = max.max(1);
+        Self {
+            state: Mutex::new(State {
+                limit: if adaptive { max.min(4) } else { max },
+                max,
+                adaptive,
+                in_flight: 0,
+                saturated: false,
+                window_start: Instant::now(),
+                window_bytes: 0,
+                window_errors: 0,
+                last_rate: 0.0,
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    pub async fn acquire(&self) -> Permit<'_> {
+        loop {
+            let notified = self.notify.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.in_flight < state.limit {
+                    state.in_flight += 1;
+                    state.saturated |= state.in_flight == state.limit;
+                    return Permit(self);
+                }
+            }
+            notified.await;
+        }
+    }
+
+    //bytes received or a failed part
+    pub fn record(&self, bytes: u64, failed: bool) {
+        let mut state = self.state.lock().unwrap();
+        if !state.adaptive {
+            return;
+        }
+        state.window_bytes += bytes;
+        state.window_errors += failed as u64;
+        let elapsed = state.window_start.elapsed();
+        if elapsed >= WINDOW && state.adjust(elapsed) {
+            drop(state);
+            self.notify.notify_waiters();
+        }
+    }
+
+    //(limit,max)
+    pub fn limit(&self) -> (u64, u64) {
+        let state = self.state.lock().unwrap();
+        (state.limit, state.max)
+    }
+}
+
+impl State {
+    //close the window, return true when the limit grew
+    fn adjust(&mut self, elapsed: Duration) -> bool {
+        let rate = self.window_bytes as f64 / elapsed.as_secs_f64();
+        let limit = self.limit;
+        if self.window_errors > 0 || (self.saturated && rate < self.last_rate * 0.8) {
+            self.limit = (self.limit / 2).max(1);
+        } else if self.saturated {
+            self.limit = (self.limit + 1).min(self.max);
+        }
+        self.last_rate = rate;
+        self.saturated = self.in_flight >= self.limit;
+        self.window_start = Instant::now();
+        self.window_bytes = 0;
+        self.window_errors = 0;
+        self.limit > limit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_adjust() {
+        let concurrency = Concurrency::new(16, true);
+        let mut state = concurrency.state.lock().unwrap();
+        assert_eq!(state.limit, 4);
+        state.in_flight = 4;
+        state.saturated = true;
+        state.window_bytes = 2000;
+        assert!(state.adjust(WINDOW));
+        assert_eq!(state.limit, 5);
+        state.saturated = true;
+        state.window_bytes = 500;
+        assert!(!state.adjust(WINDOW));
+        assert_eq!(state.limit, 2);
+        state.window_bytes = 500;
+        state.window_errors = 1;
+        assert!(!state.adjust(WINDOW));
+        assert_eq!(state.limit, 1);
+        //no more work is not a slow link
+        state.in_flight = 0;
+        state.saturated = false;
+        assert!(!state.adjust(WINDOW));
+        assert_eq!(state.limit, 1);
+        assert_eq!(Concurrency::new(16, false).limit(), (16, 16));
+    }
+}
@@ -34,6 +34,39 @@ pub fn from<P: AsRef<Path>>(path: P) -> Value {
     read_json(path)
 }
 
+//sections other than server, client and xcopy which provide a path
+pub fn catalog_names(config: &Value) -> Vec<String> {
+    const RESERVED: [&str; 3] = ["server", "client", "xcopy"];
+    config
+        .as_object()
+        .map(|sections| {
+            sections
+                .iter()
+                .filter(|(name, section)| {
+                    !RESERVED.contains(&name.as_str()) && section["path"].is_string()
+                })
+                .map(|(name, _)| name.clone())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+//catalogs listed in server.catalogs, the only sections the server may serve
+pub fn served_catalog_names(config: &Value) -> Vec<String> {
+    let catalogs = catalog_names(config);
+    config["server"]["catalogs"]
+        .as_array()
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(|x| x.as_str())
+                .filter(|x| catalogs.iter().any(|catalog| catalog == x))
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 #[cfg(test)]
 pub fn new() -> Value {
     let path = get_config_file();
@@ -61,4 +94,15 @@ mod tests {
         );
         assert_eq!(config["config"]["https_port"], json!(443));
     }
+    #[test]
+    fn test_served_catalog_names() {
+        let config = json!({
+            "server": {"catalogs": ["demo", "client", "server", "missing", 1]},
+            "client": {"path": "./recv"},
+            "demo": {"path": "./sent"},
+            "other": {"path": "./other"}
+        });
+        assert_eq!(super::served_catalog_names(&config), vec!["demo"]);
+        assert!(super::served_catalog_names(&json!({"demo": {"path": "."}})).is_empty());
+    }
 }
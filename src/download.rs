@@ -1,16 +1,33 @@
+use crate::bandwidth::ClientLimit;
 use crate::base16::base16_encode;
+use crate::chunk::{chunk_file, ChunkIndex, CHUNKING, CHUNK_MIN_SIZE};
+use crate::concurrency::Concurrency;
+use crate::filelist::{
+    FileEntry, FileList, CHUNK_LIST_NAME, INDEX_VERSION, LIST_NAME, LOCAL_CHUNK_LIST_NAME,
+    PART_LIST_NAME,
+};
 use crate::fileutil;
+use crate::journal::{part_on_disk, Journal};
 use crate::json_helper::JsonHelper;
+use crate::plan::{Plan, PlanFormat};
 use anyhow::{anyhow, Result};
 use blake3::Hasher;
 use byte_unit::Byte;
-use fileutil::{calc_parts, kill_running_exe, PartData, EOL, MAX_SPLIT_PARTS};
-use reqwest::{Response, StatusCode};
+use fileutil::{
+    calc_parts, is_safe_relative_path, kill_running_exe, link_file, replace_file,
+    set_file_mtime_and_mode, LinkMode, PartData, JOURNAL_FILE_EXT, MAX_SPLIT_PARTS,
+    STREAM_BUFFER_SIZE, TEMP_FILE_EXT,
+};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use reqwest::{Certificate, Client, Identity, Response, StatusCode};
 use serde_json::{json, Value};
-use std::io::SeekFrom;
+use std::future::Future;
 use std::path::Path;
-use tokio::fs::{self, DirBuilder, File};
-use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::fs::{self, DirBuilder, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::Semaphore;
 use tokio::task;
 
 pub fn base_url(config: &Value) -> String {
@@ -24,57 +41,235 @@ pub fn base_url(config: &Value) -> String {
         port
     )
 }
-pub async fn request(base_url: &str, params: &Value) -> Result<Response> {
+
+//the download api of a server, every request carries the credentials of the client section,
+//"token": "..." or "user": "..." with "password": "..."
+//parts fetched at once are limited to max_tasks, or adapted up to it with "adaptive_tasks": true,
+//and paced by "max_bandwidth" like "10MB" bytes per second,
+//a failed part is fetched again up to "retries" times after "retry_delay_in_millisecond" doubling,
+//a large file changed on the server is rebuilt from its local copy unless "delta_sync": false,
+//a duplicate of a local file is a hard link or a reflink with "link_mode": "hardlink"/"reflink"
+#[derive(Clone)]
+pub struct Remote {
+    client: Client,
+    base_url: String,
+    limit: Option<ClientLimit>,
+    concurrency: Arc<Concurrency>,
+    retries: u32,
+    retry_delay: Duration,
+    delta_sync: bool,
+    link_mode: LinkMode,
+}
+
+impl Remote {
+    pub async fn new(config: &Value, max_tasks: u64) -> Result<Self> {
+        let mut headers = HeaderMap::new();
+        if let Some(authorization) = authorization(config) {
+            let mut value = HeaderValue::from_str(&authorization)
+                .map_err(|_| anyhow!("token, user or password in client config is invalid"))?;
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        let mut builder = Client::builder().default_headers(headers);
+        //"client_cert" and "client_key" in pem are presented to a server verifying clients
+        let client_cert = config["client_cert"].str("");
+        if !client_cert.is_empty() {
+            let client_key = config["client_key"].str("");
+            let cert = fs::read(client_cert)
+                .await
+                .map_err(|e| anyhow!("client_cert {}: {}", client_cert, e))?;
+            let key = fs::read(client_key)
+                .await
+                .map_err(|e| anyhow!("client_key {}: {}", client_key, e))?;
+            let identity = Identity::from_pkcs8_pem(&cert, &key)
+                .map_err(|e| anyhow!("client_cert/client_key is invalid: {}", e))?;
+            builder = builder.identity(identity);
+        }
+        //"ca_cert" in pem is trusted instead of the system roots
+        let ca_cert = config["ca_cert"].str("");
+        if !ca_cert.is_empty() {
+            let cert = fs::read(ca_cert)
+                .await
+                .map_err(|e| anyhow!("ca_cert {}: {}", ca_cert, e))?;
+            let cert = Certificate::from_pem(&cert)
+                .map_err(|e| anyhow!("ca_cert {} is invalid: {}", ca_cert, e))?;
+            builder = builder
+                .tls_built_in_root_certs(false)
+                .add_root_certificate(cert);
+        }
+        let client = builder.build()?;
+        Ok(Remote {
+            client,
+            base_url: base_url(config),
+            limit: ClientLimit::new(config),
+            concurrency: Arc::new(Concurrency::new(
+                max_tasks,
+                config["adaptive_tasks"].bool(false),
+            )),
+            retries: config["retries"].u64(3) as u32,
+            retry_delay: Duration::from_millis(config["retry_delay_in_millisecond"].u64(500)),
+            delta_sync: config["delta_sync"].bool(true),
+            link_mode: LinkMode::new(config["link_mode"].str("copy")),
+        })
+    }
+}
+
+//an error worth another attempt: a 5xx response, a short part or a digest mismatch,
+//connection errors are recognized as reqwest::Error
+#[derive(Debug)]
+struct Transient(String);
+
+impl std::fmt::Display for Transient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Transient {}
+
+fn is_retryable(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<Transient>().is_some() || e.downcast_ref::<reqwest::Error>().is_some()
+}
+
+//delay before retry attempt 1,2,3..., doubled each time up to 30 seconds,
+//with a random half of it taken off so that parts failed together do not retry together
+fn backoff(retry_delay: Duration, attempt: u32) -> Duration {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    let delay = retry_delay
+        .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+        .min(Duration::from_secs(30));
+    let random = RandomState::new().build_hasher().finish();
+    delay / 2 + delay.mul_f64((random % 1000) as f64 / 2000.0)
+}
+
+//run attempt until it succeeds, fails for good or the retries of remote are used up,
+//return (result,retries)
+async fn with_retries<T, F, Fut>(remote: &Remote, mut attempt: F) -> Result<(T, u32)>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut retries = 0;
+    loop {
+        match attempt().await {
+            Ok(result) => return Ok((result, retries)),
+            Err(e) if retries < remote.retries && is_retryable(&e) => {
+                retries += 1;
+                tracing::warn!("retry {} after: {:?}", retries, e);
+                tokio::time::sleep(backoff(remote.retry_delay, retries)).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn authorization(config: &Value) -> Option<String> {
+    let token = config["token"].str("");
+    let user = config["user"].str("");
+    if !token.is_empty() {
+        Some(format!("Bearer {}", token))
+    } else if !user.is_empty() {
+        Some(format!("Bearer {}:{}", user, config["password"].str("")))
+    } else {
+        None
+    }
+}
+
+pub async fn request(remote: &Remote, params: &Value) -> Result<Response> {
     //use tracing::debug;
     let params = base16_encode(&format!("{}", params))?;
-    let url = String::from(base_url) + &params;
+    let url = remote.base_url.clone() + &params;
     //debug!("get {}", url);
-    reqwest::get(url)
+    remote
+        .client
+        .get(url)
+        .send()
         .await
-        .map_err(|e| anyhow!("download::request error {:?}", e))
+        .map_err(|e| anyhow::Error::new(e).context("download::request error"))
 }
 
-pub async fn get_full_of_file(base_url: &str, catalog: &str, file: &str) -> Result<PartData> {
-    get_part_of_file(base_url, catalog, file, 0, 0).await
+pub async fn get_full_of_file(remote: &Remote, catalog: &str, file: &str) -> Result<PartData> {
+    get_part_of_file(remote, catalog, file, 0, 0).await
 }
 
 pub async fn get_part_of_file(
-    base_url: &str,
+    remote: &Remote,
     catalog: &str,
     file: &str,
     skip: u64,
     take: u64,
 ) -> Result<PartData> {
-    let params = if take == 0 {
+    let (part, _) = with_retries(remote, || async {
+        let (skip, take, response) =
+            request_part_of_file(remote, catalog, file, skip, take).await?;
+        let bytes = response.bytes().await?.to_vec();
+        Ok((skip, take, bytes))
+    })
+    .await?;
+    Ok(part)
+}
+
+//return (skip,take,response) with the body not read yet
+async fn request_part_of_file(
+    remote: &Remote,
+    catalog: &str,
+    file: &str,
+    skip: u64,
+    take: u64,
+) -> Result<(u64, u64, Response)> {
+    let mut params = if take == 0 {
         json!({"catalog":catalog,"file":file})
     } else {
         json!({"catalog":catalog,"file":file,"skip":skip,"take":take})
     };
-    let response = request(base_url, &params).await?;
+    //without it the server sends the legacy list for clients that can not read the header
+    if file == LIST_NAME {
+        params["list_version"] = json!(INDEX_VERSION);
+    }
+    let response = request(remote, &params).await?;
     if response.status() == StatusCode::OK {
         let headers = response.headers();
-        let skip_val = headers.get("x-skip");
-        let skip = if skip_val.is_none() {
-            skip
+        let skip = if let Some(skip_val) = headers.get("x-skip") {
+            skip_val.to_str()?.parse::<u64>()?
         } else {
-            //u64::from_str_radix(skip_val.unwrap().to_str()?, 10)?
-            (skip_val.unwrap().to_str()?).parse::<u64>()?
+            skip
         };
-        let take_val = headers.get("x-take");
-        let take = if take_val.is_none() {
-            take
+        let take = if let Some(take_val) = headers.get("x-take") {
+            take_val.to_str()?.parse::<u64>()?
         } else {
-            //u64::from_str_radix(take_val.unwrap().to_str()?, 10)?
-            (take_val.unwrap().to_str()?).parse::<u64>()?
+            take
         };
-        let bytes = response.bytes().await?.to_vec();
-        Ok((skip, take, bytes))
+        Ok((skip, take, response))
+    } else if response.status() == StatusCode::UNAUTHORIZED {
+        let msg = response.text().await.unwrap_or_default();
+        Err(anyhow!(
+            "download {} of {} is unauthorized, check token or user and password in client config: {}",
+            file,
+            catalog,
+            msg
+        ))
+    } else if response.status() == StatusCode::FORBIDDEN {
+        let msg = response.text().await.unwrap_or_default();
+        Err(anyhow!(
+            "download {} of {} is forbidden, this client is not allowed by the server: {}",
+            file,
+            catalog,
+            msg
+        ))
     } else if response.status() == StatusCode::NOT_ACCEPTABLE
         && response.headers().contains_key("x-body-is-error")
     {
         let msg = response.bytes().await?;
         let msg: String = String::from_utf8(msg.to_vec())?;
         Err(anyhow!("download files fail: {}", msg))
+    } else if response.status().is_server_error() {
+        Err(anyhow!(Transient(format!(
+            "download {} of {} fail: {:?}",
+            file,
+            catalog,
+            response.status()
+        ))))
     } else {
         Err(anyhow!(
             "download files fail: unkown reason {:?}",
@@ -82,29 +277,325 @@ pub async fn get_part_of_file(
         ))
     }
 }
-//return (digest_calc,file_size_calc,parts,part_size,from_local)
-type DowloadFileReturn = (String, u64, u64, u64, bool);
+
+//stream a part from the server or a local source file into target_file_name at skip,
+//hashing it on the way, take 0 means the full file
+//return (skip,take,part_digest)
+async fn fetch_part_of_file(
+    remote: &Remote,
+    catalog: &str,
+    source_file_name: &str,
+    local_source_file_name: Option<&str>,
+    target_file_name: &str,
+    skip: u64,
+    take: u64,
+) -> Result<(u64, u64, String)> {
+    let mut digest = Hasher::new();
+    let mut size: u64 = 0;
+    //a part copied locally takes a slot too, max_tasks parts are in flight across all files
+    let _permit = remote.concurrency.acquire().await;
+    let (skip, take, mut target) = if let Some(local_source_file_name) = local_source_file_name {
+        let (skip, take, mut source) =
+            fileutil::open_part_of_file(local_source_file_name, skip, take).await?;
+        let mut target = fileutil::open_file_at(target_file_name, skip).await?;
+        let mut buffer = vec![0u8; STREAM_BUFFER_SIZE];
+        loop {
+            let n = source.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            target.write_all(&buffer[..n]).await?;
+            digest.update(&buffer[..n]);
+            size += n as u64;
+        }
+        (skip, take, target)
+    } else {
+        //failures and throughput of the parts steer the adaptive limit
+        let fetched: Result<_> = async {
+            let (skip, take, mut response) =
+                request_part_of_file(remote, catalog, source_file_name, skip, take).await?;
+            let mut target = fileutil::open_file_at(target_file_name, skip).await?;
+            while let Some(chunk) = response.chunk().await? {
+                if let Some(limit) = &remote.limit {
+                    limit.throttle(chunk.len() as u64).await;
+                }
+                target.write_all(&chunk).await?;
+                digest.update(&chunk);
+                size += chunk.len() as u64;
+                remote.concurrency.record(chunk.len() as u64, false);
+            }
+            Ok((skip, take, target))
+        }
+        .await;
+        if fetched.is_err() {
+            remote.concurrency.record(0, true);
+        }
+        fetched?
+    };
+    target.flush().await?;
+    if size != take {
+        return Err(anyhow!(Transient(format!(
+            "{} part at {} is short, expect: {}, got: {}",
+            source_file_name, skip, take, size
+        ))));
+    }
+    Ok((skip, take, format!("{}", digest.finalize().to_hex())))
+}
+
+//a part whose digest is not the expected one is fetched again like a short part
+fn verify_part(
+    part: (u64, u64, String),
+    expected: Option<&str>,
+    source_file_name: &str,
+) -> Result<(u64, u64, String)> {
+    match expected {
+        Some(expected) if part.2 != expected => Err(anyhow!(Transient(format!(
+            "{} part at {} hash check error, expect: {}, got: {}",
+            source_file_name, part.0, expected, part.2
+        )))),
+        _ => Ok(part),
+    }
+}
+
+//(parts,part_size) of entry, on the grid of its part digests when the index has them
+fn part_grid(entry: &FileEntry, part_size: u64) -> (u64, u64) {
+    if entry.parts.is_empty() {
+        calc_parts(entry.size, part_size, MAX_SPLIT_PARTS)
+    } else {
+        (entry.parts.len() as u64, entry.part_size)
+    }
+}
+
+//where each chunk of entry is found on local disk, in base_file_name, an older local copy, or
+//in any file of local_chunks
+//return [(skip,size,hash,local (file_name,offset))...]
+async fn locate_chunks<'a>(
+    base_file_name: &str,
+    entry: &'a FileEntry,
+    local_chunks: &Mutex<ChunkIndex>,
+) -> Result<Vec<(u64, u64, &'a str, Option<(String, u64)>)>> {
+    //a local copy unknown to the index is chunked first
+    let mut base_chunks = ChunkIndex::default();
+    let indexed = local_chunks.lock().unwrap().contains_file(base_file_name);
+    match fs::metadata(base_file_name).await {
+        Ok(meta) if meta.is_file() && meta.len() > 0 && !indexed => {
+            base_chunks.add(base_file_name, &chunk_file(base_file_name).await?);
+        }
+        _ => (),
+    }
+    let mut skip: u64 = 0;
+    Ok(entry
+        .chunks
+        .iter()
+        .map(|(size, hash)| {
+            let local = base_chunks
+                .get(hash, *size)
+                .or_else(|| local_chunks.lock().unwrap().get(hash, *size));
+            skip += size;
+            (skip - size, *size, hash.as_str(), local)
+        })
+        .collect())
+}
+
+//rebuild entry into the temporary file of target_file_name from the chunks locate_chunks
+//finds and fetch only the missing chunks, each checked against its hash and journaled so that
+//an interrupted run resumes with them
+//return (fetched_chunks,reused_size,retries) or None when no chunk is found locally
+async fn delta_file(
+    remote: &Remote,
+    catalog: &str,
+    source_file_name: &str,
+    base_file_name: &str,
+    target_file_name: &str,
+    entry: &FileEntry,
+    local_chunks: &Mutex<ChunkIndex>,
+) -> Result<Option<(u64, u64, u32)>> {
+    //(skip,size,hash,local (file_name,offset))
+    let plan = locate_chunks(base_file_name, entry, local_chunks).await?;
+    if plan.iter().all(|x| x.3.is_none()) {
+        return Ok(None);
+    }
+    let temp_file_name = &(String::from(target_file_name) + TEMP_FILE_EXT);
+    //part_size 0 tells a journal of chunks from one of the part grid of a full download
+    let mut journal = Journal::open(target_file_name, &entry.digest, entry.size, 0).await?;
+    if journal.is_empty() {
+        File::create(temp_file_name).await?;
+    } else {
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(temp_file_name)
+            .await?;
+    }
+    let mut reused_size: u64 = 0;
+    //(skip,size,hash) of the chunks to fetch
+    let mut missing: Vec<(u64, u64, String)> = Vec::new();
+    for (skip, size, hash, local) in plan {
+        //a chunk fetched by an interrupted run is checked on disk
+        if journal.part(skip) == Some(&(size, hash.to_string()))
+            && part_on_disk(temp_file_name, skip, size, hash).await
+        {
+            continue;
+        }
+        if let Some((local_file_name, offset)) = local {
+            //a local file changed since it was indexed is caught here
+            let copied = fileutil::copy_part_of_file_at(
+                &local_file_name,
+                temp_file_name,
+                offset,
+                size,
+                skip,
+            )
+            .await;
+            if copied.is_ok()
+                && fileutil::hash_part_of_file(temp_file_name, skip, size)
+                    .await
+                    .map(|x| x.1 == hash)
+                    .unwrap_or(false)
+            {
+                reused_size += size;
+                continue;
+            }
+        }
+        missing.push((skip, size, hash.to_string()));
+    }
+    let fetched_chunks = missing.len() as u64;
+    //the jobs own what they use, the scheduler runs them on their own tasks
+    let jobs: Vec<_> = missing
+        .into_iter()
+        .map(|(skip, take, hash)| {
+            let remote = remote.clone();
+            let catalog = catalog.to_string();
+            let source_file_name = source_file_name.to_string();
+            let temp_file_name = temp_file_name.to_string();
+            async move {
+                with_retries(&remote, || async {
+                    let part = fetch_part_of_file(
+                        &remote,
+                        &catalog,
+                        &source_file_name,
+                        None,
+                        &temp_file_name,
+                        skip,
+                        take,
+                    )
+                    .await?;
+                    verify_part(part, Some(&hash), &source_file_name)
+                })
+                .await
+            }
+        })
+        .collect();
+    let mut retries: u32 = 0;
+    let mut results = fileutil::schedule(remote.concurrency.limit().1, jobs);
+    while let Some(result) = results.recv().await {
+        let ((skip, take, hash), part_retries) = result??;
+        journal.append(skip, take, &hash).await?;
+        retries += part_retries;
+    }
+    journal.remove().await?;
+    let (file_size_calc, digest_calc) = fileutil::hash_part_of_file(temp_file_name, 0, 0).await?;
+    if file_size_calc != entry.size || digest_calc != entry.digest {
+        return Err(anyhow!(
+            "rebuilt file hash check error, expect: {}, got: {}",
+            entry.digest,
+            digest_calc
+        ));
+    }
+    Ok(Some((fetched_chunks, reused_size, retries)))
+}
+
+//filer.exe can not replace itself while running, it is written beside as filer.exe.new
+fn target_file_name(path: &str, file_name: &str) -> String {
+    if file_name.to_lowercase().ends_with("filer.exe") {
+        String::from(path) + "/" + file_name + ".new"
+    } else {
+        String::from(path) + "/" + file_name
+    }
+}
+
+//move local_file_name, an identical file under a name removed from the server, to the name
+//of entry after checking it still has the digest the local filelist.txt gives it
+async fn move_local_file(
+    path: &str,
+    entry: &FileEntry,
+    local_file_name: &str,
+) -> Result<DownloadFileReturn> {
+    let local_file_name = String::from(path) + "/" + local_file_name;
+    let target_file_name = target_file_name(path, &entry.name);
+    let (file_size, digest) = fileutil::hash_part_of_file(&local_file_name, 0, 0).await?;
+    if file_size != entry.size || digest != entry.digest {
+        return Err(anyhow!("{} changed since it was indexed", local_file_name));
+    }
+    if let Some(target_file_folder) = Path::new(&target_file_name).parent() {
+        DirBuilder::new()
+            .recursive(true)
+            .create(target_file_folder)
+            .await?;
+    }
+    fs::rename(&local_file_name, &target_file_name)
+        .await
+        .map_err(|e| {
+            anyhow!(
+                "rename {} to {} fail: {:?}",
+                local_file_name,
+                target_file_name,
+                e
+            )
+        })?;
+    //the old name is taken out of the prune list, its folders are not pruned after it
+    remove_empty_folders(path, &local_file_name).await;
+    set_file_mtime_and_mode(&target_file_name, entry.mtime, entry.mode).await?;
+    Ok(DownloadFileReturn {
+        file_size,
+        parts: 1,
+        from_local: true,
+        ..Default::default()
+    })
+}
+
+//what download_file did with one file, shown in its progress line
+#[derive(Default)]
+struct DownloadFileReturn {
+    file_size: u64,
+    parts: u64,
+    from_local: bool,
+    linked: bool,
+    //parts of an interrupted download taken from the journal
+    resumed_parts: u64,
+    retries: u32,
+    //size a delta sync took from local files and the chunks it fetched
+    reused_size: u64,
+    fetched_chunks: u64,
+}
+//chunks of local files, shared by every download of a run
+type LocalChunks = Arc<Mutex<ChunkIndex>>;
+//digest => (file_size,[file_name...],fetched), fetched once the first file is downloaded
+type DigestFiles = std::collections::HashMap<String, (u64, Vec<String>, bool)>;
+//(skip,take,part_digest,fetched,retries), fetched is false for a journaled part verified on disk
+type FetchPartReturn = (u64, u64, String, bool, u32);
 
 #[allow(clippy::too_many_arguments)]
 async fn download_file(
-    base_url: &str,
+    remote: &Remote,
     catalog: &str,
     path: &str,
-    file_name: &str,
-    file_size: u64,
+    entry: &FileEntry,
     part_size: u64,
-    digest: &str,
     source_file_name: &str,
     from_local: bool,
-) -> Result<DowloadFileReturn> {
+    local_chunks: LocalChunks,
+) -> Result<DownloadFileReturn> {
+    let file_name = entry.name.as_str();
+    let file_size = entry.size;
+    let digest = entry.digest.as_str();
     let local_source_file_name = path.to_string() + "/" + source_file_name;
     let source_file_name = String::from(source_file_name);
-    let target_file_name = if file_name.to_lowercase().ends_with("filer.exe") {
-        String::from(path) + "/" + file_name + ".new"
-    } else {
-        String::from(path) + "/" + file_name
-    };
-    let (parts, part_size) = calc_parts(file_size, part_size, MAX_SPLIT_PARTS);
+    let target_file_name = target_file_name(path, file_name);
+    //the old target stays intact until the new content is verified
+    let temp_file_name = target_file_name.clone() + TEMP_FILE_EXT;
+    let (parts, part_size) = part_grid(entry, part_size);
     // println!(
     //     ">>writing {} with size {},splited {} parts*{} ...",
     //     target_file_name, file_size, parts, part_size
@@ -116,57 +607,248 @@ async fn download_file(
         .recursive(true)
         .create(target_file_folder)
         .await?;
-    let mut target = File::create(&target_file_name).await?;
-    let mut digest_calc = Hasher::new();
-    let mut file_size_calc: u64 = 0;
-    async fn process_result(
-        result: Result<PartData>,
-        target: &mut File,
-        digest: &mut Hasher,
-        file_size: &mut u64,
-    ) -> Result<()> {
-        let (skip, take, result) = result?;
-        target.seek(SeekFrom::Start(skip)).await?;
-        target.write_all(&result).await?;
-        digest.update(&result);
-        *file_size += take;
-        Ok(())
-    }
-    if parts == 1 {
-        let result = if from_local {
-            fileutil::get_full_of_file(&local_source_file_name).await
-        } else {
-            get_full_of_file(base_url, catalog, &source_file_name).await
-        };
-        process_result(result, &mut target, &mut digest_calc, &mut file_size_calc).await?;
+    //a file that can not be rebuilt from its local copy is downloaded in full, an interrupted
+    //full download is resumed rather than replaced by a delta sync
+    if remote.delta_sync
+        && !from_local
+        && !entry.chunks.is_empty()
+        && !Journal::resumable(&target_file_name, digest, file_size, part_size).await
+    {
+        let base_file_name = String::from(path) + "/" + file_name;
+        let result = delta_file(
+            remote,
+            catalog,
+            &source_file_name,
+            &base_file_name,
+            &target_file_name,
+            entry,
+            &local_chunks,
+        )
+        .await;
+        match result {
+            Ok(Some((fetched_chunks, reused_size, retries))) => {
+                set_file_mtime_and_mode(&temp_file_name, entry.mtime, entry.mode).await?;
+                replace_file(&temp_file_name, &target_file_name).await?;
+                local_chunks
+                    .lock()
+                    .unwrap()
+                    .add(&target_file_name, &entry.chunks);
+                return Ok(DownloadFileReturn {
+                    file_size,
+                    parts,
+                    retries,
+                    reused_size,
+                    fetched_chunks,
+                    ..Default::default()
+                });
+            }
+            Ok(None) => (),
+            Err(e) => {
+                fs::remove_file(&temp_file_name).await.ok();
+                fs::remove_file(target_file_name.clone() + JOURNAL_FILE_EXT)
+                    .await
+                    .ok();
+                tracing::warn!(
+                    "delta sync of {} fail, download in full: {:?}",
+                    file_name,
+                    e
+                );
+            }
+        }
+    }
+    //a link shares the content of the local file, so it is checked first, a hard link is the
+    //same file and keeps the mtime and mode of the local one
+    if from_local && remote.link_mode != LinkMode::Copy {
+        let (size, digest_calc) =
+            fileutil::hash_part_of_file(&local_source_file_name, 0, 0).await?;
+        if size == file_size
+            && digest_calc == digest
+            && link_file(
+                &local_source_file_name,
+                &target_file_name,
+                file_size,
+                remote.link_mode,
+            )
+            .await
+        {
+            if remote.link_mode == LinkMode::Reflink {
+                set_file_mtime_and_mode(&target_file_name, entry.mtime, entry.mode).await?;
+            }
+            if !entry.chunks.is_empty() {
+                local_chunks
+                    .lock()
+                    .unwrap()
+                    .add(&target_file_name, &entry.chunks);
+            }
+            return Ok(DownloadFileReturn {
+                file_size: size,
+                parts: 1,
+                from_local: true,
+                linked: true,
+                ..Default::default()
+            });
+        }
+    }
+    //parts already written by an interrupted download are kept
+    let mut journal = if parts > 1 && !from_local {
+        Some(Journal::open(&target_file_name, digest, file_size, part_size).await?)
+    } else {
+        None
+    };
+    let local_source_file_name = if from_local {
+        Some(local_source_file_name.as_str())
+    } else {
+        None
+    };
+    let mut retries: u32 = 0;
+    //each part is checked against its digest from filelist.parts as it arrives, a single part
+    //against the file digest, a copy of a local file needs no check
+    let part_digests = if from_local {
+        Vec::new()
+    } else if parts == 1 {
+        vec![entry.digest.clone()]
     } else {
-        let mut results: Vec<task::JoinHandle<Result<PartData>>> =
-            Vec::with_capacity(parts as usize);
-        for i in 0..parts as usize {
-            let skip = i as u64 * part_size;
-            let take = part_size;
-            let source_file_name = source_file_name.clone();
-            let local_source_file_name = local_source_file_name.clone();
-            let base_url: String = base_url.into();
-            let catalog: String = catalog.into();
-            results.push(task::spawn(async move {
-                if from_local {
-                    fileutil::get_part_of_file(&local_source_file_name, skip, take).await
+        entry.parts.clone()
+    };
+    //a file without part digests is fetched again within the retries when its digest is wrong,
+    //any of its parts may be the bad one
+    let result = loop {
+        //return (digest_calc,file_size_calc,resumed_parts,retries)
+        let result: Result<(String, u64, u64, u32)> = async {
+            if journal.as_ref().map(|x| !x.is_empty()).unwrap_or(false) {
+                OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(&temp_file_name)
+                    .await?;
+            } else {
+                File::create(&temp_file_name).await?;
+            }
+            if parts == 1 {
+                let ((_, take, digest_calc), retries) = with_retries(remote, || async {
+                    let part = fetch_part_of_file(
+                        remote,
+                        catalog,
+                        &source_file_name,
+                        local_source_file_name,
+                        &temp_file_name,
+                        0,
+                        0,
+                    )
+                    .await?;
+                    verify_part(
+                        part,
+                        part_digests.first().map(|x| x.as_str()),
+                        &source_file_name,
+                    )
+                })
+                .await?;
+                return Ok((digest_calc, take, 0, retries));
+            }
+            let mut file_size_calc: u64 = 0;
+            let mut resumed_parts: u64 = 0;
+            let mut part_retries: u32 = 0;
+            //every part streams into the temporary file through its own handle
+            let mut results: Vec<task::JoinHandle<Result<FetchPartReturn>>> =
+                Vec::with_capacity(parts as usize);
+            for i in 0..parts as usize {
+                let skip = i as u64 * part_size;
+                let take = part_size;
+                let journaled = journal.as_ref().and_then(|x| x.part(skip)).cloned();
+                let expected = part_digests.get(i).cloned();
+                let temp_file_name = temp_file_name.clone();
+                let source_file_name = source_file_name.clone();
+                let local_source_file_name = local_source_file_name.map(String::from);
+                let remote = remote.clone();
+                let catalog: String = catalog.into();
+                results.push(task::spawn(async move {
+                    if let Some((take, part_digest)) = journaled {
+                        if expected.as_ref().map_or(true, |x| *x == part_digest)
+                            && part_on_disk(&temp_file_name, skip, take, &part_digest).await
+                        {
+                            return Ok((skip, take, part_digest, false, 0));
+                        }
+                    }
+                    //only this part is fetched again when it fails
+                    with_retries(&remote, || async {
+                        let part = fetch_part_of_file(
+                            &remote,
+                            &catalog,
+                            &source_file_name,
+                            local_source_file_name.as_deref(),
+                            &temp_file_name,
+                            skip,
+                            take,
+                        )
+                        .await?;
+                        verify_part(part, expected.as_deref(), &source_file_name)
+                    })
+                    .await
+                    .map(|((skip, take, part_digest), retries)| {
+                        (skip, take, part_digest, true, retries)
+                    })
+                }));
+            }
+            for result in results {
+                let (skip, take, part_digest, fetched, retries) = result.await??;
+                part_retries += retries;
+                if fetched {
+                    if let Some(journal) = journal.as_mut() {
+                        journal.append(skip, take, &part_digest).await?;
+                    }
                 } else {
-                    get_part_of_file(&base_url, &catalog, &source_file_name, skip, take).await
+                    resumed_parts += 1;
                 }
-            }));
+                file_size_calc += take;
+            }
+            //parts land out of order, so the file digest is read back from disk
+            let (_, digest_calc) = fileutil::hash_part_of_file(&temp_file_name, 0, 0).await?;
+            Ok((digest_calc, file_size_calc, resumed_parts, part_retries))
         }
-        for result in results {
-            let result = result.await?;
-            process_result(result, &mut target, &mut digest_calc, &mut file_size_calc).await?;
+        .await;
+        match result {
+            Ok((digest_calc, _, _, part_retries))
+                if digest_calc != digest
+                    && !from_local
+                    && entry.parts.is_empty()
+                    && retries + part_retries < remote.retries =>
+            {
+                retries += part_retries + 1;
+                if let Some(old) = journal.take() {
+                    old.remove().await.ok();
+                    journal =
+                        Some(Journal::open(&target_file_name, digest, file_size, part_size).await?);
+                }
+                tracing::warn!("retry {} after: {} hash check error", retries, file_name);
+                tokio::time::sleep(backoff(remote.retry_delay, retries)).await;
+            }
+            result => break result,
         }
-    }
-
-    let digest_calc = digest_calc.finalize();
-    let digest_calc = format!("{}", digest_calc.to_hex());
+    };
 
-    if file_size_calc != file_size {
+    let (digest_calc, file_size_calc, resumed_parts, part_retries) = match result {
+        Ok(result) => result,
+        Err(e) => {
+            //journaled parts in the temporary file are resumed by the next run
+            match journal {
+                Some(journal) if !journal.is_empty() => (),
+                Some(journal) => {
+                    journal.remove().await.ok();
+                    fs::remove_file(&temp_file_name).await.ok();
+                }
+                None => {
+                    fs::remove_file(&temp_file_name).await.ok();
+                }
+            }
+            return Err(e);
+        }
+    };
+    //the journal is useless once all parts are in, whether the file is good or not
+    if let Some(journal) = journal {
+        journal.remove().await?;
+    }
+    let checked = if file_size_calc != file_size {
         Err(anyhow!(
             "file size check error, expect: {}, got: {}",
             file_size,
@@ -179,93 +861,392 @@ async fn download_file(
             digest_calc
         ))
     } else {
-        Ok((digest_calc, file_size_calc, parts, part_size, from_local))
+        set_file_mtime_and_mode(&temp_file_name, entry.mtime, entry.mode).await
+    };
+    if let Err(e) = checked {
+        fs::remove_file(&temp_file_name).await.ok();
+        return Err(e);
     }
+    replace_file(&temp_file_name, &target_file_name).await?;
+    //later files of this run may share chunks with this one
+    if !entry.chunks.is_empty() {
+        local_chunks
+            .lock()
+            .unwrap()
+            .add(&target_file_name, &entry.chunks);
+    }
+    Ok(DownloadFileReturn {
+        file_size: file_size_calc,
+        parts,
+        from_local,
+        resumed_parts,
+        retries: retries + part_retries,
+        ..Default::default()
+    })
 }
 
-//(digest,size,name)
-fn parse_file_list(str: &str) -> Vec<(&str, u64, &str)> {
-    let file_list: Vec<(&str, u64, &str)> = str
-        .split(EOL)
-        .filter(|x| !x.is_empty())
-        .map(|x| {
-            let mut parts: Vec<&str> = x.split(',').collect();
-            let file_name = parts.pop().unwrap();
-            let size = parts.pop().unwrap();
-            let size = size.parse::<u64>().unwrap(); // u64::from_str_radix(&size, 10).unwrap();
-            let digest = parts.pop().unwrap();
-            (digest, size, file_name)
+//add the chunks of every local file of CHUNK_MIN_SIZE or more that no chunk list covers to
+//local_chunks, files the server never chunked, local-only files and older versions alike,
+//a file is cut again only when its size or mtime changed since filelist.local-chunks
+async fn chunk_local_files(path: &str, local_chunks: &LocalChunks, max_tasks: u64) -> Result<()> {
+    let mut local_list = FileList::default();
+    for file_name in fileutil::get_dir_file_names(path).await? {
+        let name = file_name.get(path.len() + 1..).unwrap_or_default();
+        if fileutil::is_temp_file(name)
+            || [
+                CHUNK_LIST_NAME,
+                LOCAL_CHUNK_LIST_NAME,
+                PART_LIST_NAME,
+                LIST_NAME,
+            ]
+            .contains(&name)
+            || local_chunks.lock().unwrap().contains_file(&file_name)
+        {
+            continue;
+        }
+        let meta = match fs::metadata(&file_name).await {
+            Ok(meta) if meta.len() >= CHUNK_MIN_SIZE => meta,
+            _ => continue,
+        };
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|x| x.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|x| x.as_nanos())
+            .unwrap_or(0);
+        local_list.entries.push(FileEntry {
+            digest: format!("{}-{}", meta.len(), mtime),
+            size: meta.len(),
+            name: name.to_string(),
+            ..Default::default()
+        });
+    }
+    let cache_file_name = String::from(path) + "/" + LOCAL_CHUNK_LIST_NAME;
+    let cache = fs::read_to_string(&cache_file_name)
+        .await
+        .unwrap_or_default();
+    //a list cut with other chunk sizes is cut again
+    local_list.merge_chunks(&cache, CHUNKING).ok();
+    let jobs: Vec<_> = local_list
+        .entries
+        .iter()
+        .enumerate()
+        .filter(|(_, x)| x.chunks.is_empty())
+        .map(|(i, x)| {
+            let file_name = String::from(path) + "/" + &x.name;
+            async move { (i, chunk_file(&file_name).await) }
         })
         .collect();
-    //file_list.sort_by_key(|x| x.0);
-    file_list
+    let mut results = fileutil::schedule(max_tasks, jobs);
+    while let Some(result) = results.recv().await {
+        if let (i, Ok(chunks)) = result? {
+            local_list.entries[i].chunks = chunks;
+        }
+    }
+    {
+        let mut local_chunks = local_chunks.lock().unwrap();
+        for entry in local_list.entries.iter().filter(|x| !x.chunks.is_empty()) {
+            local_chunks.add(&(String::from(path) + "/" + &entry.name), &entry.chunks);
+        }
+    }
+    fileutil::write_string_to_file_atomic(&local_list.chunks_to_string(CHUNKING), &cache_file_name)
+        .await?;
+    Ok(())
+}
+
+//file_name => (local_file_name,rename)
+type LocalSources = std::collections::HashMap<String, (String, bool)>;
+
+//the first file of a digest already here under another name that this run leaves alone
+//is taken from it, moved when that name is pruned, copied otherwise,
+//a dry run of prune leaves every local name in place so nothing is moved
+fn select_local_sources(
+    local_file_list: &std::collections::HashMap<&str, (&str, u64)>,
+    remote_file_list: &[&FileEntry],
+    prune_list: &[String],
+    prune_dry_run: bool,
+) -> LocalSources {
+    use std::collections::{HashMap, HashSet};
+    let download_names: HashSet<&str> = remote_file_list.iter().map(|x| x.name.as_str()).collect();
+    let prune_names: HashSet<&str> = if prune_dry_run {
+        HashSet::new()
+    } else {
+        prune_list.iter().map(|x| x.as_str()).collect()
+    };
+    //(digest,file_size) => [local_file_name...]
+    let mut local_names: HashMap<(&str, u64), Vec<&str>> = HashMap::new();
+    for (name, (digest, size)) in local_file_list.iter() {
+        if !download_names.contains(name) {
+            local_names.entry((digest, *size)).or_default().push(name);
+        }
+    }
+    let mut first_digests: HashSet<&str> = HashSet::new();
+    let mut local_sources = LocalSources::new();
+    for x in remote_file_list.iter() {
+        if !first_digests.insert(x.digest.as_str()) {
+            continue;
+        }
+        if let Some(names) = local_names.get_mut(&(x.digest.as_str(), x.size)) {
+            names.sort();
+            let name = names
+                .iter()
+                .find(|x| prune_names.contains(*x))
+                .unwrap_or(&names[0]);
+            local_sources.insert(
+                x.name.clone(),
+                (name.to_string(), prune_names.contains(name)),
+            );
+        }
+    }
+    local_sources
+}
+
+//remove the folders of file_name left empty up to path, stop at the first one still in use
+async fn remove_empty_folders(path: &str, file_name: &str) {
+    use tracing::info;
+    let mut folder = Path::new(file_name).parent();
+    while let Some(dir) = folder {
+        if dir == Path::new(path) || fs::remove_dir(dir).await.is_err() {
+            break;
+        }
+        info!("prune empty folder {:?}", dir);
+        folder = dir.parent();
+    }
+}
+
+//remove files deleted from the server, return removed count
+async fn prune_files(path: &str, file_names: &[String], dry_run: bool) -> usize {
+    use tracing::info;
+    let mut removed_count: usize = 0;
+    for (i, file_name) in file_names.iter().enumerate() {
+        let lower_file_name = file_name.to_lowercase();
+        if !is_safe_relative_path(file_name)
+            || lower_file_name.ends_with("filer.exe")
+            || lower_file_name.ends_with("filer.json")
+        {
+            println!("<<{: ^#4} {} ...skipped", i + 1, file_name);
+            continue;
+        }
+        let full_name = String::from(path) + "/" + file_name;
+        if dry_run {
+            println!("<<{: ^#4} {} ...would be removed", i + 1, file_name);
+            continue;
+        }
+        match fs::remove_file(&full_name).await {
+            Ok(_) => {
+                removed_count += 1;
+                info!("prune {}", full_name);
+                println!("<<{: ^#4} {} ...removed", i + 1, file_name);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                println!("<<{: ^#4} {} ...already gone", i + 1, file_name);
+            }
+            Err(e) => println!("<<{: ^#4} {} {:?}", i + 1, file_name, e),
+        }
+        remove_empty_folders(path, &full_name).await;
+    }
+    removed_count
 }
+
 pub async fn download_files(
     config: &Value,
     download_all: bool,
     max_tasks: u64,
     catalog: &str,
+    prune: bool,
+    prune_dry_run: bool,
+    dry_run: Option<PlanFormat>,
 ) -> Result<()> {
     use std::collections::{HashMap, HashSet};
     use std::ffi::OsStr;
     let client_config = &config["client"];
     let kill_running = client_config["kill_running_exe"].bool(true);
+    let prune_dry_run = prune_dry_run || client_config["prune_dry_run"].bool(false);
+    let prune = prune || prune_dry_run || client_config["prune"].bool(false);
     let catalog = client_config["catalog"].string(catalog);
-    println!(">>catalog={catalog}");
-    let base_url = base_url(client_config);
+    if dry_run.is_none() {
+        println!(">>catalog={catalog}");
+    }
+    let max_tasks = client_config["max_tasks"].u64(max_tasks);
+    let remote = Remote::new(client_config, max_tasks).await?;
     let catalog_config = &config[&catalog];
-    let part_size = catalog_config["part_size"].u64(1024 * 1024);
-    let (_, _, bytes) = get_full_of_file(&base_url, &catalog, "filelist.txt").await?;
+    let (_, _, bytes) = get_full_of_file(&remote, &catalog, LIST_NAME).await?;
     let remote_file_list_bytes = bytes.clone();
     let remote_file_list: String = String::from_utf8(bytes)?;
-    let remote_file_list: Vec<(&str, u64, &str)> = parse_file_list(&remote_file_list);
-    let file_count = remote_file_list.len();
-    let file_size = remote_file_list.iter().map(|x| x.1).sum::<u64>();
+    let mut remote_file_list = FileList::parse(&remote_file_list)
+        .map_err(|e| anyhow!("remote filelist.txt of {} is invalid: {}", catalog, e))?;
+    //part digests let a bad part be fetched again alone, servers of older versions have none
+    if let Ok((_, _, bytes)) = get_full_of_file(&remote, &catalog, PART_LIST_NAME).await {
+        if let Err(e) = remote_file_list.merge_parts(&String::from_utf8_lossy(&bytes)) {
+            eprintln!("Remote {} is ignored: {}", PART_LIST_NAME, e);
+        }
+    }
+    //chunks of large files let a changed file be rebuilt from chunks on local disk,
+    //the list is kept next to the local filelist.txt to find them in the next run
+    let mut remote_chunk_list_bytes: Option<Vec<u8>> = None;
+    if remote.delta_sync {
+        if let Ok((_, _, bytes)) = get_full_of_file(&remote, &catalog, CHUNK_LIST_NAME).await {
+            let chunks = String::from_utf8_lossy(&bytes);
+            match remote_file_list.merge_chunks(&chunks, CHUNKING) {
+                Ok(_) => remote_chunk_list_bytes = Some(bytes),
+                Err(e) => eprintln!("Remote {} is ignored: {}", CHUNK_LIST_NAME, e),
+            }
+        }
+    }
+    let part_size =
+        catalog_config["part_size"].u64(remote_file_list.part_size().unwrap_or(1024 * 1024));
+    let file_count = remote_file_list.entries.len();
+    let file_size = remote_file_list.entries.iter().map(|x| x.size).sum::<u64>();
     let path = client_config["path"].str("d:/tcsoftV6");
-    let max_tasks = client_config["max_tasks"].u64(max_tasks);
-    let local_file_list = fs::read_to_string(String::from(path) + "/filelist.txt")
+    let local_file_list = fs::read_to_string(String::from(path) + "/" + LIST_NAME)
         .await
         .unwrap_or_else(|_| "".to_owned());
-    let local_file_list: Vec<(&str, u64, &str)> = parse_file_list(&local_file_list);
+    let mut local_file_list = FileList::parse(&local_file_list).unwrap_or_else(|e| {
+        eprintln!("Local filelist.txt is ignored: {}", e);
+        FileList::default()
+    });
+    let local_chunks: LocalChunks = Default::default();
+    if remote.delta_sync {
+        let chunks = fs::read_to_string(String::from(path) + "/" + CHUNK_LIST_NAME)
+            .await
+            .unwrap_or_default();
+        if local_file_list.merge_chunks(&chunks, CHUNKING).is_ok() {
+            let mut local_chunks = local_chunks.lock().unwrap();
+            for entry in local_file_list
+                .entries
+                .iter()
+                .filter(|x| !x.chunks.is_empty())
+            {
+                local_chunks.add(&(String::from(path) + "/" + &entry.name), &entry.chunks);
+            }
+        }
+    }
 
     //(file_name,(digest,file_size))
     let local_file_list: HashMap<&str, (&str, u64)> = local_file_list
+        .entries
         .iter()
-        .filter(|x| !x.2.is_empty())
-        .map(|x| (x.2, (x.0, x.1)))
+        .map(|x| (x.name.as_str(), (x.digest.as_str(), x.size)))
         .collect();
 
-    //filter different files (digest,file_size,file_name)
-    let remote_file_list: Vec<(&str, u64, &str)> = remote_file_list
-        .into_iter()
-        .filter(|(digest, file_size, file_name)| {
-            if file_name.to_lowercase().ends_with("filer.json")
-                || file_name.to_lowercase().ends_with("filer.exe.new")
+    //files only in the local list were removed from the server
+    let mut prune_list: Vec<String> = if prune {
+        let remote_file_names: HashSet<&str> = remote_file_list
+            .entries
+            .iter()
+            .map(|x| x.name.as_str())
+            .collect();
+        let mut file_names: Vec<String> = local_file_list
+            .keys()
+            .filter(|x| !remote_file_names.contains(*x))
+            .map(|x| x.to_string())
+            .collect();
+        file_names.sort();
+        file_names
+    } else {
+        Vec::new()
+    };
+
+    //filter different files
+    let remote_file_list: Vec<&FileEntry> = remote_file_list
+        .entries
+        .iter()
+        .filter(|x| {
+            if x.name.to_lowercase().ends_with("filer.json")
+                || x.name.to_lowercase().ends_with("filer.exe.new")
             {
                 false
             } else if download_all {
                 true
             } else {
                 local_file_list
-                    .get(file_name)
+                    .get(x.name.as_str())
                     .map(|(local_digest, local_size)| {
-                        !(local_digest == digest && local_size == file_size)
+                        !(*local_digest == x.digest && *local_size == x.size)
                     })
                     .unwrap_or(true)
             }
         })
         .collect();
     let download_count = remote_file_list.len();
-    let download_size = remote_file_list.iter().map(|x| x.1).sum::<u64>();
+    let download_size = remote_file_list.iter().map(|x| x.size).sum::<u64>();
 
-    if kill_running {
-        let exe_list = remote_file_list
+    let local_sources = select_local_sources(
+        &local_file_list,
+        &remote_file_list,
+        &prune_list,
+        prune_dry_run,
+    );
+
+    let exe_list = if kill_running {
+        remote_file_list
             .iter()
-            .map(|x| Path::new(x.2))
+            .map(|x| Path::new(&x.name))
             .filter(|x| x.extension().unwrap_or_else(|| OsStr::new("")) == "exe")
             .map(|x| x.file_name().unwrap().to_str().unwrap())
             .filter(|x| x.to_lowercase() != "filer.exe")
-            .collect::<HashSet<&str>>();
+            .collect::<HashSet<&str>>()
+    } else {
+        HashSet::new()
+    };
+
+    if let Some(format) = dry_run {
+        let mut plan = Plan::new(&catalog, path);
+        let mut exe_list = exe_list.into_iter().collect::<Vec<&str>>();
+        exe_list.sort();
+        exe_list.iter().for_each(|x| plan.kill(x));
+        //the first file of a digest is downloaded or taken from a local file,
+        //the others copied from it
+        let mut first_file_names: HashMap<&str, &str> = HashMap::new();
+        for x in remote_file_list.iter() {
+            if let Some(source_file_name) = first_file_names.get(x.digest.as_str()) {
+                plan.copy(&x.name, x.size, source_file_name);
+            } else {
+                first_file_names.insert(&x.digest, &x.name);
+                match local_sources.get(&x.name) {
+                    Some((local_file_name, true)) => plan.rename(&x.name, x.size, local_file_name),
+                    Some((local_file_name, false)) => plan.copy(&x.name, x.size, local_file_name),
+                    None => {
+                        //a changed file is rebuilt from its local copy as the run would,
+                        //only its chunks not found locally count
+                        let target_file_name = String::from(path) + "/" + &x.name;
+                        let fetch_size = if remote.delta_sync
+                            && !x.chunks.is_empty()
+                            && !Journal::resumable(&target_file_name, &x.digest, x.size, part_size)
+                                .await
+                        {
+                            locate_chunks(&target_file_name, x, &local_chunks)
+                                .await
+                                .ok()
+                                .filter(|chunks| chunks.iter().any(|x| x.3.is_some()))
+                                .map(|chunks| {
+                                    chunks.iter().filter(|x| x.3.is_none()).map(|x| x.1).sum()
+                                })
+                        } else {
+                            None
+                        };
+                        match fetch_size {
+                            Some(fetch_size) => plan.delta(&x.name, x.size, fetch_size),
+                            None => plan.download(&x.name, x.size),
+                        }
+                    }
+                }
+            }
+        }
+        let renamed: HashSet<&str> = local_sources
+            .values()
+            .filter(|x| x.1)
+            .map(|x| x.0.as_str())
+            .collect();
+        prune_list
+            .iter()
+            .filter(|x| !renamed.contains(x.as_str()))
+            .for_each(|x| plan.prune(x));
+        plan.print(format);
+        return Ok(());
+    }
+
+    if kill_running {
         if !exe_list.is_empty() {
             print!("Kill running exe: ");
             for image_name in exe_list {
@@ -279,30 +1260,28 @@ pub async fn download_files(
         println!();
     }
 
-    //(digest,(file_size,[file_name...]))
-    let mut unique_digest_list: HashMap<String, (u64, Vec<String>, bool)> = HashMap::new();
-    remote_file_list
-        .iter()
-        .for_each(|(digest, file_size, file_name)| {
-            if let Some(val) = unique_digest_list.get_mut(*digest) {
-                val.1.push(file_name.to_string());
-                assert_eq!(val.0, *file_size);
-            } else {
-                unique_digest_list.insert(
-                    digest.to_string(),
-                    (*file_size, vec![file_name.to_string()], false),
-                );
-            }
-        });
+    //(digest,(file_size,[file_name...],fetched))
+    let mut unique_digest_list: DigestFiles = HashMap::new();
+    remote_file_list.iter().for_each(|x| {
+        if let Some(val) = unique_digest_list.get_mut(&x.digest) {
+            val.1.push(x.name.clone());
+            assert_eq!(val.0, x.size);
+        } else {
+            unique_digest_list.insert(x.digest.clone(), (x.size, vec![x.name.clone()], false));
+        }
+    });
 
-    use std::sync::{Arc, Mutex};
     let unique_digest_list = Arc::new(Mutex::new(unique_digest_list));
 
     //return (source_file_name,from_local)
-    let get_source_file = |file_name: &str, digest: &str| -> (String, bool) {
+    fn get_source_file(
+        unique_digest_list: &Mutex<DigestFiles>,
+        file_name: &str,
+        digest: &str,
+    ) -> (String, bool) {
         if let Ok(unique_digest_list) = unique_digest_list.lock() {
             if let Some((_, file_list, fetched)) = unique_digest_list.get(digest) {
-                let first_file_name = file_list.get(0).unwrap().clone();
+                let first_file_name = file_list.first().unwrap().clone();
                 (first_file_name, *fetched)
             } else {
                 (file_name.to_string(), false)
@@ -310,89 +1289,229 @@ pub async fn download_files(
         } else {
             (file_name.to_string(), false)
         }
-    };
-    let set_source_file = |digest: &str| -> bool {
+    }
+    //the first file of its digest, fetched before its copies
+    fn is_first_file(
+        unique_digest_list: &Mutex<DigestFiles>,
+        file_name: &str,
+        digest: &str,
+    ) -> bool {
+        match unique_digest_list.lock() {
+            Ok(unique_digest_list) => unique_digest_list
+                .get(digest)
+                .map_or(true, |x| x.1.first().map(|x| x.as_str()) == Some(file_name)),
+            Err(_) => true,
+        }
+    }
+    fn set_source_file(unique_digest_list: &Mutex<DigestFiles>, digest: &str) {
         if let Ok(mut unique_digest_list) = unique_digest_list.lock() {
             if let Some((_, _, fetched)) = unique_digest_list.get_mut(digest) {
                 *fetched = true;
-                true
-            } else {
-                false
             }
-        } else {
-            false
         }
-    };
+    }
+    //digest => gate closed when its first file is done, of the digests with copies
+    let gates: HashMap<String, Arc<Semaphore>> = unique_digest_list
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|x| x.1 .1.len() > 1)
+        .map(|x| (x.0.clone(), Arc::new(Semaphore::new(0))))
+        .collect();
 
     let mut download_error_count: usize = 0;
-    let mut i: usize = 0;
     let mut print_count: usize = 0;
+    //(file_name,retries) of the files that needed another attempt
+    let mut retried_files: Vec<(String, u32)> = Vec::new();
+    //local files moved to another name, no longer there to prune
+    let mut renamed: HashSet<String> = HashSet::new();
+    let local_sources = Arc::new(local_sources);
 
+    //a chunk of any local file is copied instead of fetched, a first download into a folder
+    //not created yet has no local files
+    if remote.delta_sync
+        && remote_file_list.iter().any(|x| !x.chunks.is_empty())
+        && fs::metadata(path).await.is_ok()
+    {
+        if let Err(e) = chunk_local_files(path, &local_chunks, max_tasks).await {
+            eprintln!("Local files are not chunked: {}", e);
+        }
+    }
     println!("Download {} ...", catalog);
-    while i < download_count {
-        let mut task_count = 0u64;
-        let mut results: Vec<(String, task::JoinHandle<Result<DowloadFileReturn>>)> =
-            Vec::with_capacity(max_tasks as usize);
-        while task_count < max_tasks && i < download_count {
-            let (digest, file_size, file_name) = *(remote_file_list
-                .get(i)
-                .ok_or_else(|| anyhow!("remote_file_list.get() error"))?);
-            let (source_file_name, from_local) = get_source_file(file_name, digest);
-            let base_url: String = base_url.clone();
-            let catalog: String = catalog.clone();
-            let path: String = path.into();
-            let digest: String = digest.into();
-            let (task_add, part_size) = calc_parts(file_size, part_size, MAX_SPLIT_PARTS);
-            let file_name_saved = file_name.into();
-            let file_name: String = file_name.into();
-            task_count += task_add;
-            let result = task::spawn(async move {
-                download_file(
-                    &base_url,
-                    &catalog,
-                    &path,
-                    &file_name,
-                    file_size,
-                    part_size,
-                    &digest,
-                    &source_file_name,
-                    from_local,
-                )
-                .await
-            });
-            results.push((file_name_saved, result));
-            i += 1;
-        }
-        for result in results {
-            print_count += 1;
-            let (file_name, result) = result;
-            let result = result.await?;
-            match result {
-                Ok((digest, file_size, parts, _part_size, from_local)) => {
-                    set_source_file(&digest);
-                    println!(
-                        ">>{: ^#4} {} {}={} pack{} ...{}",
-                        print_count,
-                        file_name,
-                        file_size,
-                        parts,
-                        if parts > 1 { "s" } else { "" },
-                        if from_local { "locally copied" } else { "" }
-                    );
+    //max_tasks files at once, each part of them takes a slot of remote.concurrency, the next
+    //file starts as soon as one is done, its source is chosen when it starts so a copy of a
+    //file fetched by then is local
+    //first files of their digests come first, so a copy waiting for its first file never
+    //keeps that one from a slot
+    let mut entries: Vec<FileEntry> = remote_file_list.iter().map(|x| (*x).clone()).collect();
+    entries.sort_by_key(|x| !is_first_file(&unique_digest_list, &x.name, &x.digest));
+    let jobs = entries.into_iter().map({
+        let remote = remote.clone();
+        let catalog = catalog.clone();
+        let path = path.to_string();
+        let unique_digest_list = unique_digest_list.clone();
+        let local_chunks = local_chunks.clone();
+        let local_sources = local_sources.clone();
+        move |entry| {
+            let remote = remote.clone();
+            let catalog = catalog.clone();
+            let path = path.clone();
+            let unique_digest_list = unique_digest_list.clone();
+            let local_chunks = local_chunks.clone();
+            let local_sources = local_sources.clone();
+            let gate = gates.get(&entry.digest).cloned();
+            async move {
+                let digest = entry.digest.clone();
+                //a copy waits until the first file of its digest is done
+                let first = is_first_file(&unique_digest_list, &entry.name, &digest);
+                if let (Some(gate), false) = (&gate, first) {
+                    gate.acquire().await.ok();
+                }
+                let done = async {
+                    let (source_file_name, from_local) =
+                        get_source_file(&unique_digest_list, &entry.name, &entry.digest);
+                    //a local file that changed since it was indexed is no source, the file is
+                    //downloaded then
+                    if let Some((local_file_name, rename)) = local_sources.get(&entry.name) {
+                        let result = if *rename {
+                            move_local_file(&path, &entry, local_file_name).await
+                        } else {
+                            download_file(
+                                &remote,
+                                &catalog,
+                                &path,
+                                &entry,
+                                part_size,
+                                local_file_name,
+                                true,
+                                local_chunks.clone(),
+                            )
+                            .await
+                        };
+                        match result {
+                            Ok(result) => {
+                                let local_source = Some((local_file_name.clone(), *rename));
+                                return (entry.name, local_source, Ok(result));
+                            }
+                            Err(e) => tracing::warn!(
+                                "reuse {} as {} fail, download it: {:?}",
+                                local_file_name,
+                                entry.name,
+                                e
+                            ),
+                        }
+                    }
+                    let result = download_file(
+                        &remote,
+                        &catalog,
+                        &path,
+                        &entry,
+                        part_size,
+                        &source_file_name,
+                        from_local,
+                        local_chunks,
+                    )
+                    .await;
+                    (entry.name, None, result)
+                }
+                .await;
+                if done.2.is_ok() {
+                    set_source_file(&unique_digest_list, &digest);
                 }
-                Err(e) => {
-                    download_error_count += 1;
-                    println!(">>{: ^#4} {} {:?}", print_count, file_name, e);
+                if let (Some(gate), true) = (&gate, first) {
+                    gate.close();
                 }
+                done
+            }
+        }
+    });
+    let mut results = fileutil::schedule(max_tasks, jobs);
+    while let Some(result) = results.recv().await {
+        print_count += 1;
+        let (file_name, local_source, result) = result?;
+        match result {
+            Ok(DownloadFileReturn {
+                file_size,
+                parts,
+                from_local,
+                linked,
+                resumed_parts,
+                retries,
+                reused_size,
+                fetched_chunks,
+            }) => {
+                if let Some((local_file_name, true)) = &local_source {
+                    renamed.insert(local_file_name.clone());
+                }
+                if retries > 0 {
+                    retried_files.push((file_name.clone(), retries));
+                }
+                println!(
+                    ">>{: ^#4} {} {}={} pack{} ...{}{}",
+                    print_count,
+                    file_name,
+                    file_size,
+                    parts,
+                    if parts > 1 { "s" } else { "" },
+                    if let Some((local_file_name, rename)) = &local_source {
+                        if *rename {
+                            format!("renamed from {}", local_file_name)
+                        } else if linked {
+                            format!("linked to {}", local_file_name)
+                        } else {
+                            format!("copied from {}", local_file_name)
+                        }
+                    } else if linked {
+                        "locally linked".to_string()
+                    } else if from_local {
+                        "locally copied".to_string()
+                    } else if reused_size > 0 {
+                        format!(
+                            "reused {} locally, fetched {} chunk{}",
+                            Byte::from_bytes(reused_size as u128).get_appropriate_unit(false),
+                            fetched_chunks,
+                            if fetched_chunks == 1 { "" } else { "s" }
+                        )
+                    } else if resumed_parts > 0 {
+                        format!("resumed {} packs", resumed_parts)
+                    } else {
+                        "".to_string()
+                    },
+                    if retries > 0 {
+                        format!(" retried {} times", retries)
+                    } else {
+                        "".to_string()
+                    }
+                );
+            }
+            Err(e) => {
+                download_error_count += 1;
+                println!(">>{: ^#4} {} {:?}", print_count, file_name, e);
             }
         }
     }
 
-    if download_count - download_error_count > 0 {
+    prune_list.retain(|x| !renamed.contains(x));
+    let prune_count = if prune_list.is_empty() {
+        0
+    } else {
+        println!(
+            "Prune {} files removed from server{} ...",
+            prune_list.len(),
+            if prune_dry_run { " (dry run)" } else { "" }
+        );
+        prune_files(path, &prune_list, prune_dry_run).await
+    };
+
+    if download_count - download_error_count > 0 || prune_count > 0 {
         println!("Write filelist.txt which content from server");
-        let file_name = String::from(path) + "/filelist.txt";
+        let file_name = String::from(path) + "/" + LIST_NAME;
         let mut file = File::create(&file_name).await?;
         file.write_all(&remote_file_list_bytes).await?;
+        if let Some(bytes) = remote_chunk_list_bytes {
+            let file_name = String::from(path) + "/" + CHUNK_LIST_NAME;
+            fs::write(&file_name, bytes).await?;
+        }
     }
     println!(
         "Total {} files with size {}, download {} files with size {} with failure count {}.",
@@ -402,12 +1521,296 @@ pub async fn download_files(
         Byte::from_bytes(download_size as u128).get_appropriate_unit(false),
         download_error_count
     );
-    if download_count > 0 {
+    if prune {
+        println!("Prune {} files, {} removed.", prune_list.len(), prune_count);
+    }
+    if !retried_files.is_empty() {
         println!(
-            "Max concurrent {} tasks, each pack size {}",
-            max_tasks,
-            Byte::from_bytes(part_size as u128).get_appropriate_unit(true)
+            "Retried {} times in {} files:",
+            retried_files.iter().map(|x| x.1).sum::<u32>(),
+            retried_files.len()
         );
+        for (file_name, retries) in retried_files.iter() {
+            println!("  {} {}", file_name, retries);
+        }
+    }
+    if download_count > 0 {
+        let (limit, max_tasks) = remote.concurrency.limit();
+        if client_config["adaptive_tasks"].bool(false) {
+            println!(
+                "Adaptive concurrent tasks ended at {} of max {}, each pack size {}",
+                limit,
+                max_tasks,
+                Byte::from_bytes(part_size as u128).get_appropriate_unit(true)
+            );
+        } else {
+            println!(
+                "Max concurrent {} tasks, each pack size {}",
+                max_tasks,
+                Byte::from_bytes(part_size as u128).get_appropriate_unit(true)
+            );
+        }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_backoff() {
+        let base = Duration::from_millis(100);
+        for (attempt, full) in [(1, 100), (2, 200), (3, 400), (20, 30000)] {
+            let delay = backoff(base, attempt);
+            assert!(delay >= Duration::from_millis(full / 2));
+            assert!(delay <= Duration::from_millis(full));
+        }
+    }
+    #[test]
+    fn test_select_local_sources() {
+        use std::collections::HashMap;
+        let entry = |name: &str, digest: &str| FileEntry {
+            name: name.into(),
+            digest: digest.into(),
+            size: 10,
+            ..Default::default()
+        };
+        //a.bin moved to new/a.bin and kept as copy.bin, b.bin copied to b2.bin, c.bin is new
+        let local_file_list: HashMap<&str, (&str, u64)> = [
+            ("a.bin", ("aa", 10)),
+            ("b.bin", ("bb", 10)),
+            ("x.bin", ("cc", 20)),
+        ]
+        .into_iter()
+        .collect();
+        let remote = [
+            entry("new/a.bin", "aa"),
+            entry("copy.bin", "aa"),
+            entry("b.bin", "bb"),
+            entry("b2.bin", "bb"),
+            entry("c.bin", "cc"),
+        ];
+        let remote = remote.iter().collect::<Vec<&FileEntry>>();
+        let download = vec![remote[0], remote[1], remote[3], remote[4]];
+        let prune_list = vec!["a.bin".to_string(), "x.bin".to_string()];
+        let sources = select_local_sources(&local_file_list, &download, &prune_list, false);
+        assert_eq!(sources.len(), 2);
+        assert_eq!(sources["new/a.bin"], ("a.bin".to_string(), true));
+        assert_eq!(sources["b2.bin"], ("b.bin".to_string(), false));
+        //a dry run of prune keeps a.bin, so it is copied rather than moved
+        let sources = select_local_sources(&local_file_list, &download, &prune_list, true);
+        assert_eq!(sources["new/a.bin"], ("a.bin".to_string(), false));
+        //a local name this run downloads again is no source
+        let sources = select_local_sources(&local_file_list, &remote, &prune_list, false);
+        assert_eq!(sources["new/a.bin"], ("a.bin".to_string(), true));
+        assert!(!sources.contains_key("b2.bin"));
+    }
+    #[tokio::test]
+    async fn test_prune_files() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path().join("catalog");
+        let path = dir.to_str().unwrap();
+        std::fs::create_dir_all(dir.join("old/sub")).unwrap();
+        std::fs::create_dir_all(dir.join("kept")).unwrap();
+        for name in [
+            "a.txt",
+            "old/sub/b.txt",
+            "kept/c.txt",
+            "kept/d.txt",
+            "filer.exe",
+            "filer.json",
+        ] {
+            std::fs::write(dir.join(name), name).unwrap();
+        }
+        std::fs::write(temp.path().join("outside.txt"), b"").unwrap();
+        let file_names = [
+            "a.txt",
+            "old/sub/b.txt",
+            "kept/c.txt",
+            "gone.txt",
+            "filer.exe",
+            "FILER.JSON",
+            "../outside.txt",
+            "/etc/hosts",
+        ]
+        .map(String::from);
+        //a dry run deletes nothing
+        assert_eq!(prune_files(path, &file_names, true).await, 0);
+        assert!(dir.join("a.txt").exists() && dir.join("old/sub/b.txt").exists());
+        assert_eq!(prune_files(path, &file_names, false).await, 3);
+        assert!(!dir.join("a.txt").exists());
+        assert!(!dir.join("kept/c.txt").exists());
+        //folders left empty go, folders with other files and the catalog root stay
+        assert!(!dir.join("old").exists());
+        assert!(dir.join("kept/d.txt").exists());
+        assert!(dir.exists());
+        assert!(dir.join("filer.exe").exists() && dir.join("filer.json").exists());
+        assert!(temp.path().join("outside.txt").exists());
+    }
+    #[tokio::test]
+    async fn test_move_local_file() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let path = dir.to_str().unwrap();
+        std::fs::create_dir_all(dir.join("old/sub")).unwrap();
+        std::fs::write(dir.join("old/sub/a.bin"), b"content").unwrap();
+        let entry = FileEntry {
+            name: "new/a.bin".into(),
+            digest: blake3::hash(b"content").to_hex().to_string(),
+            size: 7,
+            mtime: 1675000000,
+            mode: 0o644,
+            ..Default::default()
+        };
+        assert!(
+            move_local_file(path, &entry, "old/sub/a.bin")
+                .await
+                .unwrap()
+                .from_local
+        );
+        assert_eq!(std::fs::read(dir.join("new/a.bin")).unwrap(), b"content");
+        //the folders of the old name are gone with it, the catalog root stays
+        assert!(!dir.join("old").exists());
+        assert!(dir.exists());
+        //a file changed since it was indexed is left alone
+        let entry = FileEntry {
+            name: "b.bin".into(),
+            digest: blake3::hash(b"old content").to_hex().to_string(),
+            ..entry
+        };
+        assert!(move_local_file(path, &entry, "new/a.bin").await.is_err());
+        assert!(dir.join("new/a.bin").exists());
+    }
+    #[tokio::test]
+    async fn test_delta_file_from_local_files() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let path = dir.to_str().unwrap().to_string();
+        fs::create_dir_all(dir.join("old")).await.unwrap();
+        let mut seed: u32 = 7;
+        let mut random = |n: usize| -> Vec<u8> {
+            (0..n)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                    (seed >> 16) as u8
+                })
+                .collect()
+        };
+        let a = random(3 * 1024 * 1024);
+        let b = random(3 * 1024 * 1024);
+        //a local-only file and a file no chunk list covers hold the chunks of the new file
+        fs::write(dir.join("old/a.bin"), &a).await.unwrap();
+        fs::write(dir.join("b.bin"), &b).await.unwrap();
+        let local_chunks: LocalChunks = Default::default();
+        chunk_local_files(&path, &local_chunks, 4).await.unwrap();
+        let cache = fs::read_to_string(dir.join(LOCAL_CHUNK_LIST_NAME))
+            .await
+            .unwrap();
+        assert_eq!(cache.lines().count(), 3);
+        let data = [a.as_slice(), b.as_slice()].concat();
+        let mut chunker = crate::chunk::Chunker::new();
+        chunker.update(&data);
+        let entry = FileEntry {
+            digest: format!("{}", blake3::hash(&data).to_hex()),
+            size: data.len() as u64,
+            name: "new.bin".to_string(),
+            chunks: chunker.finish(),
+            ..Default::default()
+        };
+        let remote = Remote::new(&json!({}), 4).await.unwrap();
+        let temp_file_name = path.clone() + "/new.bin" + TEMP_FILE_EXT;
+        //chunks of the new file are found in both local files, only those across the
+        //boundary of a and b are missing
+        let found: Vec<_> = entry
+            .chunks
+            .iter()
+            .filter_map(|x| local_chunks.lock().unwrap().get(&x.1, x.0))
+            .map(|x| x.0)
+            .collect();
+        assert!(found.len() < entry.chunks.len());
+        assert!(found.contains(&(path.clone() + "/old/a.bin")));
+        assert!(found.contains(&(path.clone() + "/b.bin")));
+        //a file made only of local chunks is rebuilt without a server
+        let mut chunker = crate::chunk::Chunker::new();
+        chunker.update(&a);
+        let entry = FileEntry {
+            digest: format!("{}", blake3::hash(&a).to_hex()),
+            size: a.len() as u64,
+            name: "new.bin".to_string(),
+            chunks: chunker.finish(),
+            ..Default::default()
+        };
+        let target_file_name = path.clone() + "/new.bin";
+        let result = delta_file(
+            &remote,
+            "c",
+            "new.bin",
+            &target_file_name,
+            &target_file_name,
+            &entry,
+            &local_chunks,
+        )
+        .await
+        .unwrap();
+        assert_eq!(result, Some((0, a.len() as u64, 0)));
+        assert_eq!(fs::read(&temp_file_name).await.unwrap(), a);
+        //chunks an interrupted run fetched are taken from the journal, not fetched again
+        let data = [a.as_slice(), &random(1024 * 1024)].concat();
+        let mut chunker = crate::chunk::Chunker::new();
+        chunker.update(&data);
+        let entry = FileEntry {
+            digest: format!("{}", blake3::hash(&data).to_hex()),
+            size: data.len() as u64,
+            name: "new.bin".to_string(),
+            chunks: chunker.finish(),
+            ..Default::default()
+        };
+        let mut journal = Journal::open(&target_file_name, &entry.digest, entry.size, 0)
+            .await
+            .unwrap();
+        let mut temp = vec![0u8; data.len()];
+        let mut skip: usize = 0;
+        for (size, hash) in entry.chunks.iter() {
+            let end = skip + *size as usize;
+            if local_chunks.lock().unwrap().get(hash, *size).is_none() {
+                temp[skip..end].copy_from_slice(&data[skip..end]);
+                journal.append(skip as u64, *size, hash).await.unwrap();
+            }
+            skip = end;
+        }
+        drop(journal);
+        fs::write(&temp_file_name, temp).await.unwrap();
+        let result = delta_file(
+            &remote,
+            "c",
+            "new.bin",
+            &target_file_name,
+            &target_file_name,
+            &entry,
+            &local_chunks,
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.map(|x| x.0), Some(0));
+        assert_eq!(fs::read(&temp_file_name).await.unwrap(), data);
+        assert!(fs::metadata(target_file_name + JOURNAL_FILE_EXT)
+            .await
+            .is_err());
+        //the cache is trusted as long as size and mtime of a file are unchanged
+        let a_file_name = dir.join("old/a.bin");
+        let mtime = std::fs::metadata(&a_file_name).unwrap().modified().unwrap();
+        std::fs::write(&a_file_name, random(a.len())).unwrap();
+        let file = std::fs::File::options()
+            .write(true)
+            .open(&a_file_name)
+            .unwrap();
+        file.set_modified(mtime).unwrap();
+        let local_chunks: LocalChunks = Default::default();
+        chunk_local_files(&path, &local_chunks, 4).await.unwrap();
+        let (size, hash) = &entry.chunks[0];
+        assert_eq!(
+            local_chunks.lock().unwrap().get(hash, *size),
+            Some((path.clone() + "/old/a.bin", 0))
+        );
+    }
+}
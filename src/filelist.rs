@@ -0,0 +1,588 @@
+use crate::fileutil::EOL;
+use anyhow::{anyhow, Result};
+use std::fmt;
+
+//list of the files of a catalog, written by indexing in the root of the catalog
+pub const LIST_NAME: &str = "filelist.txt";
+pub const INDEX_MAGIC: &str = "#filer-index";
+pub const INDEX_VERSION: u64 = 2;
+pub const HASH_ALGORITHM: &str = "blake3";
+pub const PARTS_MAGIC: &str = "#filer-parts";
+pub const PARTS_VERSION: u64 = 1;
+//per-part digests written next to filelist.txt
+pub const PART_LIST_NAME: &str = "filelist.parts";
+pub const CHUNKS_MAGIC: &str = "#filer-chunks";
+pub const CHUNKS_VERSION: u64 = 1;
+//content-defined chunks of large files for delta sync, written next to filelist.txt
+pub const CHUNK_LIST_NAME: &str = "filelist.chunks";
+//chunks a client cut of its own files, in the format of filelist.chunks with size-mtime of
+//each file in place of its digest
+pub const LOCAL_CHUNK_LIST_NAME: &str = "filelist.local-chunks";
+
+//#filer-index,version,catalog,generated,hash,part_size
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexHeader {
+    pub version: u64,
+    pub catalog: String,
+    pub generated: u64,
+    pub hash: String,
+    pub part_size: u64,
+}
+
+//digest,size,mtime,mode,name
+//mtime and mode are 0 when read from a legacy list
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FileEntry {
+    pub digest: String,
+    pub size: u64,
+    pub mtime: u64,
+    pub mode: u32,
+    pub name: String,
+    //part size and blake3 of each part on the download grid, read from filelist.parts,
+    //empty for a file of a single part or a list without part digests
+    pub part_size: u64,
+    pub parts: Vec<String>,
+    //content-defined chunks read from filelist.chunks, empty for a file below delta_file_size
+    pub chunks: Chunks,
+}
+
+//(size,blake3) of each chunk in file order
+pub type Chunks = Vec<(u64, String)>;
+
+//(digest,part_size,parts) of a filelist.parts line
+type PartDigests = (String, u64, Vec<String>);
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FileList {
+    pub header: Option<IndexHeader>,
+    pub entries: Vec<FileEntry>,
+}
+
+impl IndexHeader {
+    pub fn new(catalog: &str, part_size: u64) -> Self {
+        use std::time::{SystemTime, UNIX_EPOCH};
+        let generated = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or(0);
+        IndexHeader {
+            version: INDEX_VERSION,
+            catalog: catalog.to_string(),
+            generated,
+            hash: HASH_ALGORITHM.to_string(),
+            part_size,
+        }
+    }
+}
+
+impl FileList {
+    pub fn new(header: IndexHeader, entries: Vec<FileEntry>) -> Self {
+        FileList {
+            header: Some(header),
+            entries,
+        }
+    }
+
+    //accept both the versioned format and the legacy digest,size,name csv
+    pub fn parse(str: &str) -> Result<Self> {
+        let mut lines = lines(str).peekable();
+        let header = match lines.peek() {
+            Some((i, line)) if line.starts_with(INDEX_MAGIC) => {
+                let header = parse_header(line).map_err(|e| line_error(*i, e))?;
+                lines.next();
+                Some(header)
+            }
+            _ => None,
+        };
+        let entries = lines
+            .map(|(i, line)| {
+                if header.is_some() {
+                    parse_entry(line)
+                } else {
+                    parse_legacy_entry(line)
+                }
+                .map_err(|e| line_error(i, e))
+            })
+            .collect::<Result<Vec<FileEntry>>>()?;
+        Ok(FileList { header, entries })
+    }
+
+    //digest,size,name lines without a header, the list as clients before version 2 read it
+    pub fn to_legacy_string(&self) -> String {
+        self.entries
+            .iter()
+            .map(|x| format!("{},{},{}", x.digest, x.size, x.name))
+            .collect::<Vec<String>>()
+            .join(EOL)
+    }
+
+    pub fn part_size(&self) -> Option<u64> {
+        self.header.as_ref().map(|x| x.part_size)
+    }
+
+    //#filer-parts,version,hash then digest,part_size,part digests split by space,name
+    //for every file of more than one part
+    pub fn parts_to_string(&self) -> String {
+        let mut result = format!("{},{},{}", PARTS_MAGIC, PARTS_VERSION, HASH_ALGORITHM);
+        for entry in self.entries.iter().filter(|x| !x.parts.is_empty()) {
+            result.push_str(EOL);
+            result.push_str(&format!(
+                "{},{},{},{}",
+                entry.digest,
+                entry.part_size,
+                entry.parts.join(" "),
+                escape(&entry.name)
+            ));
+        }
+        result
+    }
+
+    //fill part digests of the entries from a filelist.parts,
+    //a line whose digest differs from the entry is stale and left out
+    pub fn merge_parts(&mut self, str: &str) -> Result<()> {
+        use std::collections::HashMap;
+        let mut lines = lines(str);
+        match lines.next() {
+            Some((i, line)) => parse_parts_header(line).map_err(|e| line_error(i, e))?,
+            None => return Ok(()),
+        }
+        let mut parts: HashMap<String, PartDigests> = HashMap::new();
+        for (i, line) in lines {
+            let (name, part) = parse_parts_entry(line).map_err(|e| line_error(i, e))?;
+            parts.insert(name, part);
+        }
+        for entry in self.entries.iter_mut() {
+            if let Some((digest, part_size, part_digests)) = parts.remove(&entry.name) {
+                if digest == entry.digest {
+                    entry.part_size = part_size;
+                    entry.parts = part_digests;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    //#filer-chunks,version,hash,min,avg,max chunk size
+    //then digest,chunks as size:hash split by space,name for every chunked file
+    pub fn chunks_to_string(&self, chunking: [u64; 3]) -> String {
+        let mut result = format!(
+            "{},{},{},{},{},{}",
+            CHUNKS_MAGIC, CHUNKS_VERSION, HASH_ALGORITHM, chunking[0], chunking[1], chunking[2]
+        );
+        for entry in self.entries.iter().filter(|x| !x.chunks.is_empty()) {
+            let chunks = entry
+                .chunks
+                .iter()
+                .map(|(size, hash)| format!("{}:{}", size, hash))
+                .collect::<Vec<String>>();
+            result.push_str(EOL);
+            result.push_str(&format!(
+                "{},{},{}",
+                entry.digest,
+                chunks.join(" "),
+                escape(&entry.name)
+            ));
+        }
+        result
+    }
+
+    //fill chunks of the entries from a filelist.chunks cut with the same chunking,
+    //a line whose digest differs from the entry is stale and left out
+    pub fn merge_chunks(&mut self, str: &str, chunking: [u64; 3]) -> Result<()> {
+        use std::collections::HashMap;
+        let mut lines = lines(str);
+        match lines.next() {
+            Some((i, line)) => parse_chunks_header(line, chunking).map_err(|e| line_error(i, e))?,
+            None => return Ok(()),
+        }
+        //name => (digest,chunks)
+        let mut chunks: HashMap<String, (String, Chunks)> = HashMap::new();
+        for (i, line) in lines {
+            let (name, chunk) = parse_chunks_entry(line).map_err(|e| line_error(i, e))?;
+            chunks.insert(name, chunk);
+        }
+        for entry in self.entries.iter_mut() {
+            if let Some((digest, file_chunks)) = chunks.remove(&entry.name) {
+                if digest == entry.digest {
+                    entry.chunks = file_chunks;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+//(line index,line) of the lines that are not empty
+fn lines(str: &str) -> impl Iterator<Item = (usize, &str)> {
+    str.split('\n')
+        .map(|x| x.strip_suffix('\r').unwrap_or(x))
+        .enumerate()
+        .filter(|(_, x)| !x.is_empty())
+}
+
+impl fmt::Display for IndexHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{},{},{},{}",
+            INDEX_MAGIC,
+            self.version,
+            escape(&self.catalog),
+            self.generated,
+            escape(&self.hash),
+            self.part_size
+        )
+    }
+}
+
+impl fmt::Display for FileEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{},{:o},{}",
+            self.digest,
+            self.size,
+            self.mtime,
+            self.mode,
+            escape(&self.name)
+        )
+    }
+}
+
+impl fmt::Display for FileList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut sep = "";
+        if let Some(header) = &self.header {
+            write!(f, "{}", header)?;
+            sep = EOL;
+        }
+        for entry in &self.entries {
+            if self.header.is_some() {
+                write!(f, "{}{}", sep, entry)?;
+            } else {
+                write!(f, "{}{},{},{}", sep, entry.digest, entry.size, entry.name)?;
+            }
+            sep = EOL;
+        }
+        Ok(())
+    }
+}
+
+fn line_error(i: usize, e: anyhow::Error) -> anyhow::Error {
+    anyhow!("file list line {}: {}", i + 1, e)
+}
+
+fn parse_header(line: &str) -> Result<IndexHeader> {
+    let fields = split_fields(line)?;
+    if fields.len() != 6 {
+        return Err(anyhow!("expect 6 header fields, got {}", fields.len()));
+    }
+    let version = parse_u64(&fields[1], "version")?;
+    if version > INDEX_VERSION {
+        return Err(anyhow!(
+            "index version {} is newer than supported version {}",
+            version,
+            INDEX_VERSION
+        ));
+    }
+    let hash = fields[4].clone();
+    if hash != HASH_ALGORITHM {
+        return Err(anyhow!("unsupported hash algorithm {}", hash));
+    }
+    Ok(IndexHeader {
+        version,
+        catalog: fields[2].clone(),
+        generated: parse_u64(&fields[3], "generated")?,
+        hash,
+        part_size: parse_u64(&fields[5], "part_size")?,
+    })
+}
+
+fn parse_parts_header(line: &str) -> Result<()> {
+    let fields = split_fields(line)?;
+    if fields.len() != 3 || fields[0] != PARTS_MAGIC {
+        return Err(anyhow!("invalid part list header"));
+    }
+    let version = parse_u64(&fields[1], "version")?;
+    if version > PARTS_VERSION {
+        return Err(anyhow!(
+            "part list version {} is newer than supported version {}",
+            version,
+            PARTS_VERSION
+        ));
+    }
+    if fields[2] != HASH_ALGORITHM {
+        return Err(anyhow!("unsupported hash algorithm {}", fields[2]));
+    }
+    Ok(())
+}
+
+fn parse_chunks_header(line: &str, chunking: [u64; 3]) -> Result<()> {
+    let fields = split_fields(line)?;
+    if fields.len() != 6 || fields[0] != CHUNKS_MAGIC {
+        return Err(anyhow!("invalid chunk list header"));
+    }
+    let version = parse_u64(&fields[1], "version")?;
+    if version > CHUNKS_VERSION {
+        return Err(anyhow!(
+            "chunk list version {} is newer than supported version {}",
+            version,
+            CHUNKS_VERSION
+        ));
+    }
+    if fields[2] != HASH_ALGORITHM {
+        return Err(anyhow!("unsupported hash algorithm {}", fields[2]));
+    }
+    let sizes = fields[3..]
+        .iter()
+        .map(|x| parse_u64(x, "chunk size"))
+        .collect::<Result<Vec<u64>>>()?;
+    if sizes != chunking {
+        return Err(anyhow!(
+            "chunk sizes {:?} differ from {:?} of this version",
+            sizes,
+            chunking
+        ));
+    }
+    Ok(())
+}
+
+//return (name,(digest,chunks))
+fn parse_chunks_entry(line: &str) -> Result<(String, (String, Chunks))> {
+    let mut fields = split_fields(line)?;
+    if fields.len() != 3 {
+        return Err(anyhow!("expect 3 fields, got {}", fields.len()));
+    }
+    let name = fields.pop().unwrap_or_default();
+    if name.is_empty() {
+        return Err(anyhow!("file name is empty"));
+    }
+    let chunks = fields[1]
+        .split(' ')
+        .map(|x| {
+            let (size, hash) = x
+                .split_once(':')
+                .ok_or_else(|| anyhow!("invalid chunk {:?}", x))?;
+            Ok((parse_u64(size, "chunk size")?, hash.to_string()))
+        })
+        .collect::<Result<Chunks>>()?;
+    Ok((name, (fields[0].clone(), chunks)))
+}
+
+//return (name,(digest,part_size,parts))
+fn parse_parts_entry(line: &str) -> Result<(String, PartDigests)> {
+    let mut fields = split_fields(line)?;
+    if fields.len() != 4 {
+        return Err(anyhow!("expect 4 fields, got {}", fields.len()));
+    }
+    let name = fields.pop().unwrap_or_default();
+    if name.is_empty() {
+        return Err(anyhow!("file name is empty"));
+    }
+    let part_size = parse_u64(&fields[1], "part_size")?;
+    let parts: Vec<String> = fields[2].split(' ').map(String::from).collect();
+    if part_size == 0 || parts.len() < 2 {
+        return Err(anyhow!("expect more than one part of size above 0"));
+    }
+    Ok((name, (fields[0].clone(), part_size, parts)))
+}
+
+fn parse_entry(line: &str) -> Result<FileEntry> {
+    let mut fields = split_fields(line)?;
+    if fields.len() != 5 {
+        return Err(anyhow!("expect 5 fields, got {}", fields.len()));
+    }
+    let name = fields.pop().unwrap_or_default();
+    if name.is_empty() {
+        return Err(anyhow!("file name is empty"));
+    }
+    Ok(FileEntry {
+        digest: fields[0].clone(),
+        size: parse_u64(&fields[1], "size")?,
+        mtime: parse_u64(&fields[2], "mtime")?,
+        mode: u32::from_str_radix(&fields[3], 8)
+            .map_err(|e| anyhow!("invalid mode {:?}: {}", fields[3], e))?,
+        name,
+        ..Default::default()
+    })
+}
+
+//digest,size,name where name is the rest of the line
+fn parse_legacy_entry(line: &str) -> Result<FileEntry> {
+    let mut fields = line.splitn(3, ',');
+    let digest = fields.next().unwrap_or_default();
+    let size = fields.next().ok_or_else(|| anyhow!("size not found"))?;
+    let name = fields
+        .next()
+        .ok_or_else(|| anyhow!("file name not found"))?;
+    if name.is_empty() {
+        return Err(anyhow!("file name is empty"));
+    }
+    Ok(FileEntry {
+        digest: digest.to_string(),
+        size: parse_u64(size, "size")?,
+        name: name.to_string(),
+        ..Default::default()
+    })
+}
+
+fn parse_u64(field: &str, name: &str) -> Result<u64> {
+    field
+        .parse::<u64>()
+        .map_err(|e| anyhow!("invalid {} {:?}: {}", name, field, e))
+}
+
+fn escape(field: &str) -> String {
+    let mut result = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => result.push_str("\\\\"),
+            ',' => result.push_str("\\,"),
+            '\r' => result.push_str("\\r"),
+            '\n' => result.push_str("\\n"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+fn split_fields(line: &str) -> Result<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('\\') => field.push('\\'),
+                Some(',') => field.push(','),
+                Some('r') => field.push('\r'),
+                Some('n') => field.push('\n'),
+                Some(c) => return Err(anyhow!("invalid escape \\{}", c)),
+                None => return Err(anyhow!("unterminated escape at end of line")),
+            },
+            ',' => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_file_list_round_trip() {
+        let list = FileList::new(
+            IndexHeader {
+                version: INDEX_VERSION,
+                catalog: "demo".into(),
+                generated: 1676000000,
+                hash: HASH_ALGORITHM.into(),
+                part_size: 1024000,
+            },
+            vec![FileEntry {
+                digest: "af13".into(),
+                size: 12,
+                mtime: 1675000000,
+                mode: 0o644,
+                name: "sub/a,b\\c.txt".into(),
+                ..Default::default()
+            }],
+        );
+        let str = list.to_string();
+        assert_eq!(
+            str,
+            "#filer-index,2,demo,1676000000,blake3,1024000\r\n\
+             af13,12,1675000000,644,sub/a\\,b\\\\c.txt"
+        );
+        assert_eq!(FileList::parse(&str).unwrap(), list);
+    }
+    #[test]
+    fn test_part_list_round_trip() {
+        let mut list = FileList::parse(
+            "#filer-index,2,demo,1676000000,blake3,1024\r\n\
+             af13,3000,1675000000,644,big\\,1.bin\r\n\
+             be27,12,1675000000,644,a.txt\r\n\
+             c0de,2048,1675000000,644,old.bin",
+        )
+        .unwrap();
+        list.entries[0].part_size = 1024;
+        list.entries[0].parts = vec!["p1".into(), "p2".into(), "p3".into()];
+        let str = list.parts_to_string();
+        assert_eq!(
+            str,
+            "#filer-parts,1,blake3\r\naf13,1024,p1 p2 p3,big\\,1.bin"
+        );
+        let mut parsed = FileList::parse(&list.to_string()).unwrap();
+        //old.bin changed since its parts were listed
+        parsed
+            .merge_parts(&(str + "\r\nffff,1024,p1 p2,old.bin"))
+            .unwrap();
+        assert_eq!(parsed, list);
+        assert!(parsed
+            .merge_parts("#filer-parts,1,blake3\r\naf13,0,p1,x")
+            .is_err());
+        assert!(parsed.merge_parts("#filer-parts,2,blake3").is_err());
+    }
+    #[test]
+    fn test_chunk_list_round_trip() {
+        let mut list = FileList::parse(
+            "#filer-index,2,demo,1676000000,blake3,1024\r\n\
+             af13,3000,1675000000,644,db\\,1.bin\r\n\
+             be27,12,1675000000,644,a.txt",
+        )
+        .unwrap();
+        list.entries[0].chunks = vec![(1000, "c1".into()), (2000, "c2".into())];
+        let str = list.chunks_to_string([10, 100, 1000]);
+        assert_eq!(
+            str,
+            "#filer-chunks,1,blake3,10,100,1000\r\naf13,1000:c1 2000:c2,db\\,1.bin"
+        );
+        let mut parsed = FileList::parse(&list.to_string()).unwrap();
+        parsed.merge_chunks(&str, [10, 100, 1000]).unwrap();
+        assert_eq!(parsed, list);
+        assert!(parsed.merge_chunks(&str, [10, 200, 1000]).is_err());
+        assert!(parsed
+            .merge_chunks(
+                "#filer-chunks,1,blake3,10,100,1000\r\naf13,1000,x",
+                [10, 100, 1000]
+            )
+            .is_err());
+    }
+    #[test]
+    fn test_file_list_legacy() {
+        let list = FileList::parse("af13,12,a,b.txt\r\n\r\nbe27,0,c.txt").unwrap();
+        assert!(list.header.is_none());
+        assert_eq!(list.entries.len(), 2);
+        assert_eq!(list.entries[0].name, "a,b.txt");
+        assert_eq!(list.entries[1].size, 0);
+    }
+    #[test]
+    fn test_file_list_to_legacy() {
+        let list = FileList::parse(
+            "#filer-index,2,demo,1676000000,blake3,1024\r\n\
+             af13,3000,1675000000,644,sub/big.bin\r\n\
+             be27,12,1675000000,644,a.txt",
+        )
+        .unwrap();
+        let str = list.to_legacy_string();
+        assert_eq!(str, "af13,3000,sub/big.bin\r\nbe27,12,a.txt");
+        let legacy = FileList::parse(&str).unwrap();
+        assert!(legacy.header.is_none());
+        assert_eq!(legacy.entries[0].name, "sub/big.bin");
+        assert_eq!(legacy.entries[1].digest, "be27");
+    }
+    #[test]
+    fn test_file_list_error_line() {
+        let err = FileList::parse("af13,12,a.txt\r\nbe27,x,c.txt").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "file list line 2: invalid size \"x\": invalid digit found in string"
+        );
+        let err = FileList::parse("#filer-index,9,demo,0,blake3,1024").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("file list line 1: index version 9"));
+    }
+}
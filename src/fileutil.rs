@@ -1,16 +1,47 @@
 #![allow(dead_code)]
 
+#[cfg(feature = "index")]
+use crate::chunk::{Chunker, CHUNKING};
+#[cfg(feature = "index")]
+use crate::filelist::{
+    Chunks, FileEntry, FileList, IndexHeader, CHUNK_LIST_NAME, PART_LIST_NAME,
+};
 use anyhow::{anyhow, Result};
 use byte_unit::Byte;
+use std::future::Future;
 use std::io::SeekFrom;
-use tokio::fs::{self, File};
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
-use tokio::task;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+#[cfg(feature = "index")]
+use std::collections::HashMap;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, Take};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::{self, JoinError};
 use futures_util::{future::BoxFuture, FutureExt};
 
 pub type PartData = (u64, u64, Vec<u8>);
 pub const EOL: &str = "\r\n";
 pub const MAX_SPLIT_PARTS: u64 = 128;
+pub const TEMP_FILE_EXT: &str = ".filer-tmp";
+pub const JOURNAL_FILE_EXT: &str = ".filer-journal";
+//buffer of streamed transfers, bounds the memory a part takes whatever its size
+pub const STREAM_BUFFER_SIZE: usize = 256 * 1024;
+
+//relative path without root, prefix or .. components, safe to join onto a base folder
+pub fn is_safe_relative_path(name: &str) -> bool {
+    use std::path::{Component, Path};
+    !name.is_empty()
+        && !name.contains('\\')
+        && Path::new(name)
+            .components()
+            .all(|x| matches!(x, Component::Normal(_)))
+}
+
+//files filer leaves beside targets while working, never indexed
+pub fn is_temp_file(file_name: &str) -> bool {
+    file_name.ends_with(TEMP_FILE_EXT) || file_name.ends_with(JOURNAL_FILE_EXT)
+}
 
 pub async fn get_file_size(file_name: &str) -> Result<u64> {
     let meta = fs::metadata(file_name).await?;
@@ -46,8 +77,227 @@ pub async fn get_part_of_file(file_name: &str, skip: u64, take: u64) -> Result<P
     }
 }
 
+//open file_name positioned at skip and limited to take bytes, take 0 means to the end
+//return (skip,take,reader), take is cut at the end of file
+pub async fn open_part_of_file(file_name: &str, skip: u64, take: u64) -> Result<(u64, u64, Take<File>)> {
+    let mut file = File::open(file_name).await?;
+    let size = file.metadata().await?.len();
+    let skip = skip.min(size);
+    let take = if take == 0 { size - skip } else { take.min(size - skip) };
+    if skip > 0 {
+        file.seek(SeekFrom::Start(skip)).await?;
+    }
+    Ok((skip, take, file.take(take)))
+}
+
+//open an existing file for writing at skip without truncating it,
+//so that parts can be written concurrently through their own handles
+pub async fn open_file_at(file_name: &str, skip: u64) -> Result<File> {
+    let mut file = OpenOptions::new().write(true).open(file_name).await?;
+    if skip > 0 {
+        file.seek(SeekFrom::Start(skip)).await?;
+    }
+    Ok(file)
+}
+
+//copy take bytes of source_file_name from skip into target_file_name at the same offset
+//return (skip,take) actually copied
+pub async fn copy_part_of_file(
+    source_file_name: &str,
+    target_file_name: &str,
+    skip: u64,
+    take: u64,
+) -> Result<(u64, u64)> {
+    copy_part_of_file_at(source_file_name, target_file_name, skip, take, skip).await
+}
+
+//copy take bytes of source_file_name from skip into target_file_name at target_skip
+//return (skip,take) actually copied
+pub async fn copy_part_of_file_at(
+    source_file_name: &str,
+    target_file_name: &str,
+    skip: u64,
+    take: u64,
+    target_skip: u64,
+) -> Result<(u64, u64)> {
+    let (skip, take, source) = open_part_of_file(source_file_name, skip, take).await?;
+    let mut source = BufReader::with_capacity(STREAM_BUFFER_SIZE, source);
+    let mut target = open_file_at(target_file_name, target_skip).await?;
+    let copied = tokio::io::copy_buf(&mut source, &mut target).await?;
+    target.flush().await?;
+    if copied != take {
+        return Err(anyhow!(
+            "{} part at {} is short, expect: {}, got: {}",
+            source_file_name,
+            skip,
+            take,
+            copied
+        ));
+    }
+    Ok((skip, take))
+}
+
+//copy_part_of_file that hashes the bytes on their way to target_file_name
+//return (skip,take,digest) of the part copied
+#[cfg(feature = "xcopy")]
+pub async fn copy_and_hash_part_of_file(
+    source_file_name: &str,
+    target_file_name: &str,
+    skip: u64,
+    take: u64,
+) -> Result<(u64, u64, String)> {
+    let (skip, take, mut source) = open_part_of_file(source_file_name, skip, take).await?;
+    let mut target = open_file_at(target_file_name, skip).await?;
+    let mut digest = blake3::Hasher::new();
+    let mut buffer = vec![0u8; STREAM_BUFFER_SIZE];
+    let mut copied: u64 = 0;
+    loop {
+        let n = source.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        digest.update(&buffer[..n]);
+        target.write_all(&buffer[..n]).await?;
+        copied += n as u64;
+    }
+    target.flush().await?;
+    if copied != take {
+        return Err(anyhow!(
+            "{} part at {} is short, expect: {}, got: {}",
+            source_file_name,
+            skip,
+            take,
+            copied
+        ));
+    }
+    Ok((skip, take, format!("{}", digest.finalize().to_hex())))
+}
+
+//how a copy of a local file is made, "link_mode" of the client or xcopy section:
+//"copy" writes the bytes again, "hardlink" gives the same file another name,
+//"reflink" clones its blocks copy on write (FICLONE of btrfs/xfs on linux)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkMode {
+    Copy,
+    Hardlink,
+    Reflink,
+}
+
+impl LinkMode {
+    //an unknown mode copies
+    pub fn new(mode: &str) -> Self {
+        match mode.to_lowercase().as_str() {
+            "hardlink" => LinkMode::Hardlink,
+            "reflink" => LinkMode::Reflink,
+            _ => LinkMode::Copy,
+        }
+    }
+}
+
+//make target_file_name a link to source_file_name in mode through a temporary sibling renamed
+//over it once it has expected_size, false if the file system or platform does not support it,
+//the caller copies the bytes then
+pub async fn link_file(
+    source_file_name: &str,
+    target_file_name: &str,
+    expected_size: u64,
+    mode: LinkMode,
+) -> bool {
+    if mode == LinkMode::Copy {
+        return false;
+    }
+    //linked by an earlier run already
+    if mode == LinkMode::Hardlink && is_same_file(source_file_name, target_file_name).await {
+        return get_file_size(target_file_name).await.ok() == Some(expected_size);
+    }
+    let temp_file_name = String::from(target_file_name) + TEMP_FILE_EXT;
+    fs::remove_file(&temp_file_name).await.ok();
+    let linked = {
+        let source_file_name = source_file_name.to_string();
+        let temp_file_name = temp_file_name.clone();
+        task::spawn_blocking(move || match mode {
+            LinkMode::Hardlink => std::fs::hard_link(&source_file_name, &temp_file_name),
+            _ => reflink_file(&source_file_name, &temp_file_name),
+        })
+        .await
+    };
+    if !matches!(linked, Ok(Ok(()))) {
+        return false;
+    }
+    if get_file_size(&temp_file_name).await.ok() != Some(expected_size) {
+        fs::remove_file(&temp_file_name).await.ok();
+        return false;
+    }
+    if replace_file(&temp_file_name, target_file_name).await.is_err() {
+        return false;
+    }
+    //rename between two names of one file does nothing and leaves the temporary name
+    fs::remove_file(&temp_file_name).await.ok();
+    true
+}
+
+//both names are the same file on one device
+pub async fn is_same_file(file_name: &str, other_file_name: &str) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        match (fs::metadata(file_name).await, fs::metadata(other_file_name).await) {
+            (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+            _ => false,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (file_name, other_file_name);
+        false
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn reflink_file(source_file_name: &str, target_file_name: &str) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let source = std::fs::File::open(source_file_name)?;
+    let target = std::fs::File::create(target_file_name)?;
+    //SAFETY: both descriptors stay open for the call
+    let ret = unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE as _, source.as_raw_fd()) };
+    if ret == -1 {
+        let e = std::io::Error::last_os_error();
+        drop(target);
+        std::fs::remove_file(target_file_name).ok();
+        return Err(e);
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink_file(_source_file_name: &str, _target_file_name: &str) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+//blake3 of take bytes from skip, read through a bounded buffer
+//return (take,digest), take is cut at the end of file
+#[cfg(any(feature = "index", feature = "download", feature = "xcopy"))]
+pub async fn hash_part_of_file(file_name: &str, skip: u64, take: u64) -> Result<(u64, String)> {
+    let (_, take, mut source) = open_part_of_file(file_name, skip, take).await?;
+    let mut digest = blake3::Hasher::new();
+    let mut buffer = vec![0u8; STREAM_BUFFER_SIZE];
+    let mut size: u64 = 0;
+    loop {
+        let n = source.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        digest.update(&buffer[..n]);
+        size += n as u64;
+    }
+    if size != take {
+        return Err(anyhow!("{} is short, expect: {}, got: {}", file_name, take, size));
+    }
+    Ok((take, format!("{}", digest.finalize().to_hex())))
+}
+
 //Async recursive version  
-pub fn get_dir_file_names(path: &str) -> BoxFuture<Result<Vec<String>>> {
+pub fn get_dir_file_names(path: &str) -> BoxFuture<'_, Result<Vec<String>>> {
     async move {
         let mut results: Vec<String> = Vec::new();
         let mut entries = tokio::fs::read_dir(&path).await?;
@@ -88,69 +338,113 @@ pub fn get_dir_file_names(path: &str) -> Result<Vec<String>> {
 */
 
 #[cfg(feature = "index")]
-//return [(file_name,size,digest)...]
+//file_name => entry of the previous index with its part digests and chunks
+pub type DigestCache = HashMap<String, FileEntry>;
+
+#[cfg(feature = "index")]
+//(file_size,digest,part_digests,chunks)
+type FileDigests = (u64, String, Vec<String>, Chunks);
+
+#[cfg(feature = "index")]
+//return [FileEntry...], FileEntry.name is the full file name
 pub async fn get_dir_index(
     path: &str,
     part_size: u64,
+    delta_file_size: u64,
     max_tasks: u64,
     show_progress: bool,
-) -> Result<Vec<(String, u64, String)>> {
+    cache: Arc<DigestCache>,
+) -> Result<Vec<FileEntry>> {
     let path = String::from(path);
     let files = get_dir_file_names(&path).await?;
     let file_count = files.len();
-    let mut results: Vec<(String, u64, String)> = Vec::with_capacity(file_count);
+    //(position in files,entry)
+    let mut results: Vec<(usize, FileEntry)> = Vec::with_capacity(file_count);
     let mut calc_error_count: usize = 0;
+    let mut cached_count: usize = 0;
     let mut print_count: u64 = 0;
-    let mut i: usize = 0;
-
-    while i < file_count {
-        let mut task_count = 0u64;
-        let mut tasks: Vec<task::JoinHandle<Result<(String, u64, String)>>> =
-            Vec::with_capacity(file_count);
-        while task_count < max_tasks && i < file_count {
-            let file = files.get(i).ok_or_else(||anyhow!("digest files.get() error"))?;
-            let file_name = file.clone();
-            let task = task::spawn(async move {
-                get_file_size_and_digest(&file_name, part_size, max_tasks)
-                    .await
-                    .map(|(size, digest)| (file_name, size, digest))
-            });
-            tasks.push(task);
-            task_count += 1;
-            i += 1;
+    //one file per slot, progress in completion order, the list keeps the order of files
+    let jobs = files.into_iter().enumerate().map(move |(i, file_name)| {
+        let cache = cache.clone();
+        async move {
+            let result = get_file_index(&file_name, part_size, delta_file_size, &cache).await;
+            (i, result)
         }
-        for task in tasks {
-            let result = task.await?;
-            print_count += 1;
-            match result {
-                Ok((file_name, file_size, digest)) => {
-                    if show_progress {
-                        println!(
-                            ">>{: ^#4} {} {} ...",
-                            print_count,
-                            &file_name,
-                            Byte::from_bytes(file_size as u128).get_appropriate_unit(false)
-                        );
-                    }
-                    results.push((file_name, file_size, digest));
+    });
+    let mut tasks = schedule(max_tasks, jobs);
+    while let Some(result) = tasks.recv().await {
+        let (i, result) = result?;
+        print_count += 1;
+        match result {
+            Ok((entry, cached)) => {
+                if cached {
+                    cached_count += 1;
                 }
-                Err(e) => {
-                    calc_error_count += 1;
-                    if show_progress {
-                        println!(">>{: ^#4} {:?}", print_count, e);
-                    }
+                if show_progress {
+                    println!(
+                        ">>{: ^#4} {} {} ...{}",
+                        print_count,
+                        &entry.name,
+                        Byte::from_bytes(entry.size as u128).get_appropriate_unit(false),
+                        if cached { "unchanged" } else { "" }
+                    );
+                }
+                results.push((i, entry));
+            }
+            Err(e) => {
+                calc_error_count += 1;
+                if show_progress {
+                    println!(">>{: ^#4} {:?}", print_count, e);
                 }
             }
         }
     }
+    if show_progress && cached_count > 0 {
+        println!(
+            "Total digest reused for unchanged files count {}",
+            cached_count
+        );
+    }
     if show_progress && calc_error_count > 0 {
         println!("Total digest calc error count {}", calc_error_count);
     }
-    Ok(results)
+    results.sort_by_key(|x| x.0);
+    Ok(results.into_iter().map(|x| x.1).collect())
+}
+
+//run jobs with at most max_tasks of them at once, the next job starts as soon as one finished
+//instead of waiting for a whole batch, results come back in completion order,
+//the parts of a file take their slots from a semaphore shared by all files
+pub fn schedule<I, F, T>(max_tasks: u64, jobs: I) -> mpsc::UnboundedReceiver<Result<T, JoinError>>
+where
+    I: IntoIterator<Item = F>,
+    I::IntoIter: Send + 'static,
+    F: Future<Output = T> + Send + 'static,
+    T: Send + 'static,
+{
+    let max_tasks = max_tasks.clamp(1, u32::MAX as u64);
+    let semaphore = Arc::new(Semaphore::new(max_tasks as usize));
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let jobs = jobs.into_iter();
+    task::spawn(async move {
+        for job in jobs {
+            let permit = match semaphore.clone().acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => break,
+            };
+            let sender = sender.clone();
+            task::spawn(async move {
+                let result = task::spawn(job).await;
+                drop(permit);
+                sender.send(result).ok();
+            });
+        }
+    });
+    receiver
 }
 
 pub fn calc_parts(file_size: u64, part_size: u64, max_split_parts: u64) -> (u64, u64) {
-    let parts = (file_size + part_size - 1) / part_size;
+    let parts = file_size.div_ceil(part_size);
     if parts <= max_split_parts {
         (parts, part_size)
     } else {
@@ -181,42 +475,249 @@ pub async fn get_dir_file_size(path: &str) -> Result<Vec<(String, u64)>> {
     Ok(results)
 }
 
+//(file_name,(file_size,mtime)), temporary files are skipped
+#[cfg(feature = "index")]
+pub type DirSnapshot = HashMap<String, (u64, u64)>;
+
+#[cfg(feature = "index")]
+pub async fn get_dir_snapshot(path: &str) -> Result<DirSnapshot> {
+    let files = get_dir_file_names(path).await?;
+    let mut results = DirSnapshot::with_capacity(files.len());
+    for file_name in files {
+        if is_temp_file(&file_name) {
+            continue;
+        }
+        //file may be removed while walking the folder
+        if let Ok(meta) = fs::metadata(&file_name).await {
+            let mtime = get_file_mtime(&meta);
+            results.insert(file_name, (meta.len(), mtime));
+        }
+    }
+    Ok(results)
+}
+
+//seconds since UNIX_EPOCH, 0 if the platform can not tell
+pub fn get_file_mtime(meta: &std::fs::Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+        .map(|x| x.as_secs())
+        .unwrap_or(0)
+}
+
+//restore mtime and permission bits recorded in the index, 0 means unknown
+pub async fn set_file_mtime_and_mode(file_name: &str, mtime: u64, mode: u32) -> Result<()> {
+    use std::time::Duration;
+    let file_name = file_name.to_string();
+    task::spawn_blocking(move || -> Result<()> {
+        if mtime > 0 {
+            let file = std::fs::File::options().write(true).open(&file_name)?;
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))?;
+        }
+        #[cfg(unix)]
+        if mode > 0 {
+            use std::os::unix::fs::PermissionsExt;
+            //keep owner writable so that the next update can replace it
+            let permissions = std::fs::Permissions::from_mode(mode | 0o200);
+            std::fs::set_permissions(&file_name, permissions)?;
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+        Ok(())
+    })
+    .await?
+}
+
+//unix permission bits, readonly flag mapped to 0o444/0o644 elsewhere
+pub fn get_file_mode(meta: &std::fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        meta.permissions().mode() & 0o7777
+    }
+    #[cfg(not(unix))]
+    {
+        if meta.permissions().readonly() {
+            0o444
+        } else {
+            0o644
+        }
+    }
+}
+
 #[cfg(feature = "index")]
-pub async fn get_file_size_and_digest(
+//return (FileEntry,from_cache), a file of more than one part on the download grid
+//carries the digest of each part, a file of delta_file_size or more its chunks
+pub async fn get_file_index(
     file_name: &str,
     part_size: u64,
-    max_tasks: u64,
-) -> Result<(u64, String)> {
-    use blake3::Hasher;
-    let file_size = get_file_size(file_name).await?;
-    let (parts, part_size) = calc_parts(file_size, part_size, max_tasks / 2);
-    // println!(
-    //     "Calc MD5 for {} with size {},splited {} parts*{} ...",
-    //     file_name, file_size, parts, part_size
-    // );
+    delta_file_size: u64,
+    cache: &DigestCache,
+) -> Result<(FileEntry, bool)> {
+    let meta = fs::metadata(file_name).await?;
+    let mut entry = FileEntry {
+        size: meta.len(),
+        mtime: get_file_mtime(&meta),
+        mode: get_file_mode(&meta),
+        name: file_name.to_string(),
+        ..Default::default()
+    };
+    let (parts, grid_part_size) = calc_parts(entry.size, part_size, MAX_SPLIT_PARTS);
+    let chunked = delta_file_size > 0 && entry.size >= delta_file_size;
+    if let Some(cached) = cache.get(file_name) {
+        if cached.size == entry.size
+            && cached.mtime == entry.mtime
+            && entry.mtime > 0
+            && (parts <= 1
+                || (cached.part_size == grid_part_size && cached.parts.len() as u64 == parts))
+            && (!chunked || !cached.chunks.is_empty())
+        {
+            entry.digest = cached.digest.clone();
+            entry.part_size = cached.part_size;
+            entry.parts = cached.parts.clone();
+            if chunked {
+                entry.chunks = cached.chunks.clone();
+            }
+            return Ok((entry, true));
+        }
+    }
+    let (file_size, digest, part_digests, chunks) =
+        hash_file_and_parts(file_name, grid_part_size, chunked).await?;
+    entry.size = file_size;
+    entry.digest = digest;
+    entry.chunks = chunks;
+    //a file that grew or shrank while read is on another grid, it goes without part digests
+    if !part_digests.is_empty()
+        && calc_parts(file_size, part_size, MAX_SPLIT_PARTS)
+            == (part_digests.len() as u64, grid_part_size)
+    {
+        entry.part_size = grid_part_size;
+        entry.parts = part_digests;
+    }
+    Ok((entry, false))
+}
 
-    let mut digest = Hasher::new();
-    let mut results: Vec<task::JoinHandle<Result<PartData>>> = Vec::with_capacity(parts as usize);
-    if parts == 1 {
-        let (_, _, part) = get_full_of_file(file_name).await?;
-        digest.update(&part);
-    } else {
-        for i in 0..parts as usize {
-            let skip = i as u64 * part_size;
-            let take = part_size;
-            let file_name: String = file_name.into();
-            let result = task::spawn(async move { get_part_of_file(&file_name, skip, take).await });
-            results.push(result);
+#[cfg(feature = "index")]
+//digests of the previous index with its filelist.parts and filelist.chunks,
+//legacy lists carry no mtime and are never reused, nor is a file whose mtime is not before
+//the second the previous index started, it may have changed again within that second
+pub async fn read_digest_cache(path: &str, list_file_name: &str) -> DigestCache {
+    let content = fs::read_to_string(String::from(path) + "/" + list_file_name)
+        .await
+        .unwrap_or_default();
+    let parts = fs::read_to_string(String::from(path) + "/" + PART_LIST_NAME)
+        .await
+        .unwrap_or_default();
+    let chunks = fs::read_to_string(String::from(path) + "/" + CHUNK_LIST_NAME)
+        .await
+        .unwrap_or_default();
+    FileList::parse(&content)
+        .map(|mut list| {
+            //files are hashed again for their part digests when the part list is unusable
+            if list.merge_parts(&parts).is_err() {
+                list.entries.iter_mut().for_each(|x| x.parts.clear());
+            }
+            if list.merge_chunks(&chunks, CHUNKING).is_err() {
+                list.entries.iter_mut().for_each(|x| x.chunks.clear());
+            }
+            let generated = list.header.as_ref().map(|x| x.generated).unwrap_or(0);
+            list.entries
+                .into_iter()
+                .filter(|x| x.mtime > 0 && x.mtime < generated)
+                .map(|x| (String::from(path) + "/" + &x.name, x))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(feature = "index")]
+//buffers of STREAM_BUFFER_SIZE read ahead of the slowest hasher of a file
+const HASH_BUFFERS_AHEAD: usize = 4;
+
+#[cfg(feature = "index")]
+//read the file once through a STREAM_BUFFER_SIZE buffer for its digest, the digest of each
+//part of part_size and its chunks when chunked, part_digests is empty for a file of one part,
+//the file digest, the part digests and the chunks take each buffer on their own tasks
+pub async fn hash_file_and_parts(
+    file_name: &str,
+    part_size: u64,
+    chunked: bool,
+) -> Result<FileDigests> {
+    use blake3::Hasher;
+    type Buffer = Arc<Vec<u8>>;
+    let part_size = part_size.max(1);
+    let (digest_tx, mut digest_rx) = mpsc::channel::<Buffer>(HASH_BUFFERS_AHEAD);
+    let digest = task::spawn(async move {
+        let mut digest = Hasher::new();
+        while let Some(buffer) = digest_rx.recv().await {
+            digest.update(&buffer);
         }
-        for result in results {
-            let result = result.await?;
-            let (_, _, part) = result?;
-            digest.update(&part);
+        format!("{}", digest.finalize().to_hex())
+    });
+    //(buffer,last buffer of its part), a buffer never spans two parts
+    let (part_tx, mut part_rx) = mpsc::channel::<(Buffer, bool)>(HASH_BUFFERS_AHEAD);
+    let part_digests = task::spawn(async move {
+        let mut part_digests: Vec<String> = Vec::new();
+        let mut part_digest = Hasher::new();
+        let mut hashed = false;
+        while let Some((buffer, last)) = part_rx.recv().await {
+            part_digest.update(&buffer);
+            hashed = true;
+            if last {
+                part_digests.push(format!("{}", part_digest.finalize().to_hex()));
+                part_digest.reset();
+                hashed = false;
+            }
+        }
+        if hashed {
+            part_digests.push(format!("{}", part_digest.finalize().to_hex()));
+        }
+        part_digests
+    });
+    let (chunk_tx, mut chunk_rx) = mpsc::channel::<Buffer>(HASH_BUFFERS_AHEAD);
+    let chunks = task::spawn(async move {
+        let mut chunker = chunked.then(Chunker::new);
+        while let Some(buffer) = chunk_rx.recv().await {
+            if let Some(chunker) = chunker.as_mut() {
+                chunker.update(&buffer);
+            }
+        }
+        chunker.map(Chunker::finish).unwrap_or_default()
+    });
+    let read = async {
+        let mut source = File::open(file_name).await?;
+        let mut file_size: u64 = 0;
+        loop {
+            let left = part_size - file_size % part_size;
+            let mut buffer = vec![0u8; left.min(STREAM_BUFFER_SIZE as u64) as usize];
+            let n = source.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            buffer.truncate(n);
+            file_size += n as u64;
+            let buffer = Arc::new(buffer);
+            let sent = digest_tx.send(buffer.clone()).await.is_ok()
+                && part_tx
+                    .send((buffer.clone(), file_size % part_size == 0))
+                    .await
+                    .is_ok()
+                && (!chunked || chunk_tx.send(buffer).await.is_ok());
+            if !sent {
+                return Err(anyhow!("hash {} fail: a hasher stopped", file_name));
+            }
         }
+        Ok(file_size)
+    };
+    let file_size = read.await;
+    //the hashers finish once their senders are gone
+    drop((digest_tx, part_tx, chunk_tx));
+    let (digest, mut part_digests, chunks) = (digest.await?, part_digests.await?, chunks.await?);
+    let file_size = file_size?;
+    if part_digests.len() <= 1 {
+        part_digests.clear();
     }
-    let digest = digest.finalize();
-    let digest = format!("{}", digest.to_hex());
-    Ok((file_size, digest))
+    Ok((file_size, digest, part_digests, chunks))
 }
 
 // async fn get_file_mpsc(
@@ -226,7 +727,7 @@ pub async fn get_file_size_and_digest(
 //     target_file_name: &str,
 // ) -> Result<u64> {
 //     use tokio::sync::mpsc;
-//     let parts = (source_file_size + part_size - 1) / part_size;
+//     let parts = source_file_size.div_ceil(part_size);
 //     let source_file_name = String::from(source_file_name);
 //     println!(
 //         "get {} save as {} with size {},splited {} parts*{} ...",
@@ -272,13 +773,35 @@ pub async fn write_string_to_file(str: &str, file_name: &str) -> Result<bool> {
     target.write_all(&bytes).await?;
     Ok(true)
 }
+
+//write to a temporary sibling first, readers never see a half-written file
+pub async fn write_string_to_file_atomic(str: &str, file_name: &str) -> Result<bool> {
+    let temp_file_name = String::from(file_name) + TEMP_FILE_EXT;
+    write_string_to_file(str, &temp_file_name).await?;
+    replace_file(&temp_file_name, file_name).await?;
+    Ok(true)
+}
+
+//rename a verified temporary file over the target, the temporary file is removed on failure
+pub async fn replace_file(temp_file_name: &str, file_name: &str) -> Result<()> {
+    if let Err(e) = fs::rename(temp_file_name, file_name).await {
+        fs::remove_file(temp_file_name).await.ok();
+        return Err(anyhow!(
+            "rename {} to {} fail: {:?}",
+            temp_file_name,
+            file_name,
+            e
+        ));
+    }
+    Ok(())
+}
 pub async fn get_file(
     source_file_name: &str,
     source_file_size: u64,
     part_size: u64,
     target_file_name: &str,
 ) -> Result<u64> {
-    let parts = (source_file_size + part_size - 1) / part_size;
+    let parts = source_file_size.div_ceil(part_size);
     let source_file_name = String::from(source_file_name);
     println!(
         "get {} save as {} with size {},splited {} parts*{} ...",
@@ -327,7 +850,7 @@ pub async fn get_file_and_digest(
     target_file_name: &str,
 ) -> Result<(u64, String)> {
     use blake3::Hasher;
-    let parts = (source_file_size + part_size - 1) / part_size;
+    let parts = source_file_size.div_ceil(part_size);
     let source_file_name = String::from(source_file_name);
     println!(
         "get {} save as {} with size {},splited {} parts*{} ...",
@@ -377,51 +900,80 @@ pub async fn get_file_and_digest(
 }
 
 #[cfg(feature = "index")]
+#[allow(clippy::too_many_arguments)]
 pub async fn refresh_dir_files_digest(
     path: &str,
+    catalog: &str,
     list_file_name: &str,
     part_size: u64,
+    delta_file_size: u64,
     max_tasks: u64,
     show_repeat: bool,
+    full: bool,
+    show_progress: bool,
 ) -> Result<()> {
-    use std::collections::HashMap;
     let path_len = path.len();
-    println!("Calc digest for files in {}...", path);
-    //(file_name,file_size,digest)
-    let results = get_dir_index(path, part_size, max_tasks, true).await?;
+    //taken before any file is read, a file modified in or after this second is hashed again
+    //by the next run as its mtime in seconds may not change with a later write
+    let header = IndexHeader::new(catalog, part_size);
+    let cache = if full {
+        DigestCache::new()
+    } else {
+        read_digest_cache(path, list_file_name).await
+    };
+    if show_progress {
+        println!("Calc digest for files in {}...", path);
+    }
+    let results = get_dir_index(
+        path,
+        part_size,
+        delta_file_size,
+        max_tasks,
+        show_progress,
+        Arc::new(cache),
+    )
+    .await?;
     let list_file_name = path.to_lowercase() + "/" + list_file_name;
-    let file_list_iter = results
-        .iter()
-        .filter(|x| x.0.to_lowercase() != list_file_name);
-    let total_size = file_list_iter
-        .clone()
-        .fold(0, |sum, (_, file_size, _)| sum + file_size);
+    let parts_file_name = path.to_lowercase() + "/" + PART_LIST_NAME;
+    let chunks_file_name = path.to_lowercase() + "/" + CHUNK_LIST_NAME;
+    let file_list_iter = results.iter().filter(|x| {
+        let file_name = x.name.to_lowercase();
+        file_name != list_file_name
+            && file_name != parts_file_name
+            && file_name != chunks_file_name
+            && !is_temp_file(&file_name)
+    });
+    let total_size = file_list_iter.clone().fold(0, |sum, x| sum + x.size);
     let total_size_with_unit = Byte::from_bytes(total_size as u128).get_appropriate_unit(false);
-    let file_list_joined = file_list_iter
+    let entries = file_list_iter
         .clone()
-        .map(|x| {
-            let file_name = x.0.get(path_len + 1..).unwrap();
-            format!("{},{},{}", x.2, x.1, file_name)
+        .map(|x| FileEntry {
+            name: x.name.get(path_len + 1..).unwrap().to_string(),
+            ..x.clone()
         })
-        .fold("".to_string(), |joined, x| {
-            let sep = if joined.is_empty() { "" } else { EOL };
-            joined + sep + &x
-        });
-    write_string_to_file(&file_list_joined, &list_file_name).await?;
-    println!(
-        "\nTotal {} files with size {},digest checksum write to {}",
-        results.len(),
-        total_size_with_unit,
-        &list_file_name
-    );
+        .collect::<Vec<FileEntry>>();
+    let file_list = FileList::new(header, entries);
+    //the part and chunk lists go first, a client never sees a new filelist.txt with old ones
+    write_string_to_file_atomic(&file_list.parts_to_string(), &parts_file_name).await?;
+    write_string_to_file_atomic(&file_list.chunks_to_string(CHUNKING), &chunks_file_name)
+        .await?;
+    write_string_to_file_atomic(&file_list.to_string(), &list_file_name).await?;
+    if show_progress {
+        println!(
+            "\nTotal {} files with size {},digest checksum write to {}",
+            file_list.entries.len(),
+            total_size_with_unit,
+            &list_file_name
+        );
+    }
     if show_repeat {
         let mut unique_digest_list: HashMap<String, (u64, Vec<String>)> = HashMap::new();
-        file_list_iter.for_each(|(file_name, file_size, digest)| {
-            if let Some(val) = unique_digest_list.get_mut(digest) {
-                val.1.push(file_name.clone());
-                assert_eq!(val.0, *file_size);
+        file_list_iter.for_each(|x| {
+            if let Some(val) = unique_digest_list.get_mut(&x.digest) {
+                val.1.push(x.name.clone());
+                assert_eq!(val.0, x.size);
             } else {
-                unique_digest_list.insert(digest.clone(), (*file_size, vec![file_name.clone()]));
+                unique_digest_list.insert(x.digest.clone(), (x.size, vec![x.name.clone()]));
             }
         });
         let unique_digest_iter = unique_digest_list.iter();
@@ -502,3 +1054,170 @@ pub async fn kill_running_exe(image_name: &str) -> Result<(i32, String)> {
         .ok_or_else(||anyhow!("kill_running_exe get exit_code fail"))?;
     Ok((exit_code, String::from(image_name)))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::Duration;
+    #[tokio::test]
+    async fn test_schedule() {
+        //sleep in ms of each job
+        let jobs = [80, 10, 10, 10, 10, 40];
+        let in_flight = Arc::new(AtomicU64::new(0));
+        let peak = Arc::new(AtomicU64::new(0));
+        let jobs = jobs.into_iter().enumerate().map({
+            let in_flight = in_flight.clone();
+            let peak = peak.clone();
+            move |(i, ms)| {
+                let in_flight = in_flight.clone();
+                let peak = peak.clone();
+                async move {
+                    let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(ms)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    i
+                }
+            }
+        });
+        let mut results = schedule(3, jobs);
+        let mut order = Vec::new();
+        while let Some(result) = results.recv().await {
+            order.push(result.unwrap());
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), 3);
+        assert_eq!(order.len(), 6);
+        //the slow first job does not hold back the ones after it
+        assert_eq!(order, vec![1, 2, 3, 4, 5, 0]);
+    }
+    #[tokio::test]
+    async fn test_link_file() {
+        assert_eq!(LinkMode::new("HardLink"), LinkMode::Hardlink);
+        assert_eq!(LinkMode::new("reflink"), LinkMode::Reflink);
+        assert_eq!(LinkMode::new("symlink"), LinkMode::Copy);
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let source = dir.join("source").to_str().unwrap().to_string();
+        let target = dir.join("target").to_str().unwrap().to_string();
+        std::fs::write(&source, b"same content").unwrap();
+        assert!(!link_file(&source, &target, 12, LinkMode::Copy).await);
+        //a file left at the target is replaced by the link
+        std::fs::write(&target, b"old").unwrap();
+        assert!(link_file(&source, &target, 12, LinkMode::Hardlink).await);
+        assert_eq!(std::fs::read(&target).unwrap(), b"same content");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert_eq!(std::fs::metadata(&source).unwrap().nlink(), 2);
+            assert!(is_same_file(&source, &target).await);
+        }
+        //linking again leaves no temporary file behind
+        assert!(link_file(&source, &target, 12, LinkMode::Hardlink).await);
+        assert!(std::fs::metadata(target.clone() + TEMP_FILE_EXT).is_err());
+        //a source of another size is no link
+        std::fs::remove_file(&target).unwrap();
+        assert!(!link_file(&source, &target, 13, LinkMode::Hardlink).await);
+        assert!(std::fs::metadata(&target).is_err());
+        assert!(std::fs::metadata(target.clone() + TEMP_FILE_EXT).is_err());
+        //a file system without clones leaves no target behind
+        if !link_file(&source, &target, 12, LinkMode::Reflink).await {
+            assert!(std::fs::metadata(&target).is_err());
+        } else {
+            assert_eq!(std::fs::read(&target).unwrap(), b"same content");
+        }
+        assert!(std::fs::metadata(target.clone() + TEMP_FILE_EXT).is_err());
+    }
+    #[test]
+    fn test_is_safe_relative_path() {
+        assert!(is_safe_relative_path("a.txt"));
+        assert!(is_safe_relative_path("sub/dir/a.txt"));
+        assert!(!is_safe_relative_path(""));
+        assert!(!is_safe_relative_path("../a.txt"));
+        assert!(!is_safe_relative_path("sub/../../a.txt"));
+        assert!(!is_safe_relative_path("./a.txt"));
+        assert!(!is_safe_relative_path("/etc/passwd"));
+        assert!(!is_safe_relative_path("sub\\..\\..\\a.txt"));
+        assert!(!is_safe_relative_path("c:\\windows\\a.txt"));
+    }
+    #[cfg(feature = "index")]
+    #[tokio::test]
+    async fn test_read_digest_cache() {
+        use crate::filelist::LIST_NAME;
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let path = dir.to_str().unwrap();
+        std::fs::write(
+            dir.join(LIST_NAME),
+            "#filer-index,2,demo,1676000000,blake3,1024\r\n\
+             af13,12,1675999999,644,old.txt\r\n\
+             be27,12,1676000000,644,same-second.txt\r\n\
+             c0de,12,0,644,no-mtime.txt",
+        )
+        .unwrap();
+        let cache = read_digest_cache(path, LIST_NAME).await;
+        //a file modified in the second the index started may have changed after it was read
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache[&(path.to_string() + "/old.txt")].digest, "af13");
+    }
+    #[cfg(feature = "index")]
+    #[tokio::test]
+    async fn test_hash_file_and_parts() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let file_name = dir.join("a.bin").to_str().unwrap().to_string();
+        let data = (0..10500u32)
+            .map(|x| (x * 7 % 251) as u8)
+            .collect::<Vec<u8>>();
+        std::fs::write(&file_name, &data).unwrap();
+        //the last part is short
+        let (size, digest, parts, chunks) =
+            hash_file_and_parts(&file_name, 1000, true).await.unwrap();
+        assert_eq!(size, 10500);
+        assert_eq!(digest, blake3::hash(&data).to_hex().to_string());
+        assert_eq!(parts.len(), 11);
+        assert_eq!(parts[10], blake3::hash(&data[10000..]).to_hex().to_string());
+        assert_eq!(chunks.iter().map(|x| x.0).sum::<u64>(), 10500);
+        let (_, digest, parts, chunks) =
+            hash_file_and_parts(&file_name, 20000, false).await.unwrap();
+        assert_eq!(digest, blake3::hash(&data).to_hex().to_string());
+        assert!(parts.is_empty() && chunks.is_empty());
+        //parts larger than the stream buffer are hashed across several buffers
+        let data = (0..700000u32)
+            .map(|x| (x * 7 % 251) as u8)
+            .collect::<Vec<u8>>();
+        std::fs::write(&file_name, &data).unwrap();
+        let (size, _, parts, _) = hash_file_and_parts(&file_name, 300000, false)
+            .await
+            .unwrap();
+        assert_eq!(size, 700000);
+        assert_eq!(parts.len(), 3);
+        assert_eq!(
+            parts[1],
+            blake3::hash(&data[300000..600000]).to_hex().to_string()
+        );
+        assert_eq!(parts[2], blake3::hash(&data[600000..]).to_hex().to_string());
+    }
+    #[cfg(feature = "xcopy")]
+    #[tokio::test]
+    async fn test_copy_and_hash_part_of_file() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let source = dir.join("source").to_str().unwrap().to_string();
+        let target = dir.join("target").to_str().unwrap().to_string();
+        std::fs::write(&source, b"0123456789").unwrap();
+        std::fs::write(&target, b"").unwrap();
+        let (skip, take, digest) = copy_and_hash_part_of_file(&source, &target, 4, 4)
+            .await
+            .unwrap();
+        assert_eq!((skip, take), (4, 4));
+        assert_eq!(digest, blake3::hash(b"4567").to_hex().to_string());
+        assert_eq!(hash_part_of_file(&target, 4, 4).await.unwrap().1, digest);
+        //the last part is cut at the end of the source
+        let (_, take, _) = copy_and_hash_part_of_file(&source, &target, 8, 4)
+            .await
+            .unwrap();
+        assert_eq!(take, 2);
+        assert_eq!(std::fs::read(&target).unwrap(), b"\x00\x00\x00\x00456789");
+    }
+}
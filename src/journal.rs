@@ -0,0 +1,197 @@
+use crate::fileutil::{hash_part_of_file, EOL, JOURNAL_FILE_EXT};
+use anyhow::Result;
+use std::collections::HashMap;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+//Completed parts of a download in progress, kept beside the target file
+//first line: digest,size,part_size  following lines: skip,take,part_digest
+pub(crate) struct Journal {
+    file_name: String,
+    file: File,
+    parts: HashMap<u64, (u64, String)>,
+}
+
+impl Journal {
+    //reuse the journal left by an interrupted download of the same file version,
+    //otherwise start a new one
+    pub async fn open(
+        target_file_name: &str,
+        digest: &str,
+        size: u64,
+        part_size: u64,
+    ) -> Result<Self> {
+        let file_name = String::from(target_file_name) + JOURNAL_FILE_EXT;
+        let header = format!("{},{},{}", digest, size, part_size);
+        let parts = read_parts(&file_name, &header).await;
+        let file = if parts.is_empty() {
+            let mut file = File::create(&file_name).await?;
+            file.write_all(header.as_bytes()).await?;
+            file.flush().await?;
+            file
+        } else {
+            OpenOptions::new().append(true).open(&file_name).await?
+        };
+        Ok(Journal {
+            file_name,
+            file,
+            parts,
+        })
+    }
+
+    //whether an interrupted download of the same file version and layout left parts to resume,
+    //the journal is left as it is
+    pub async fn resumable(
+        target_file_name: &str,
+        digest: &str,
+        size: u64,
+        part_size: u64,
+    ) -> bool {
+        let file_name = String::from(target_file_name) + JOURNAL_FILE_EXT;
+        let header = format!("{},{},{}", digest, size, part_size);
+        !read_parts(&file_name, &header).await.is_empty()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parts.is_empty()
+    }
+
+    //(take,part_digest) of the completed part starting at skip
+    pub fn part(&self, skip: u64) -> Option<&(u64, String)> {
+        self.parts.get(&skip)
+    }
+
+    pub async fn append(&mut self, skip: u64, take: u64, part_digest: &str) -> Result<()> {
+        let line = format!("{}{},{},{}", EOL, skip, take, part_digest);
+        //a tokio file writes in the background, the line is on disk once flushed
+        self.file.write_all(line.as_bytes()).await?;
+        self.file.flush().await?;
+        self.parts.insert(skip, (take, part_digest.to_string()));
+        Ok(())
+    }
+
+    pub async fn remove(self) -> Result<()> {
+        drop(self.file);
+        fs::remove_file(&self.file_name).await?;
+        Ok(())
+    }
+}
+
+//whether the journaled part at skip is still in file_name, a part the crash left half written
+//or changed since fails and is fetched again
+pub(crate) async fn part_on_disk(file_name: &str, skip: u64, take: u64, part_digest: &str) -> bool {
+    hash_part_of_file(file_name, skip, take)
+        .await
+        .map(|x| x == (take, part_digest.to_string()))
+        .unwrap_or(false)
+}
+
+//parts of the journal file_name when its first line is header, none otherwise
+async fn read_parts(file_name: &str, header: &str) -> HashMap<u64, (u64, String)> {
+    let content = fs::read_to_string(file_name).await.unwrap_or_default();
+    let mut lines = content.split(EOL);
+    if lines.next() == Some(header) {
+        lines.filter_map(parse_part).collect()
+    } else {
+        HashMap::new()
+    }
+}
+
+//a line cut short or left zero-filled by a crash fails to parse and is ignored
+fn parse_part(line: &str) -> Option<(u64, (u64, String))> {
+    let mut fields = line.split(',');
+    let skip = fields.next()?.parse::<u64>().ok()?;
+    let take = fields.next()?.parse::<u64>().ok()?;
+    let part_digest = fields.next()?;
+    if part_digest.len() != blake3::OUT_LEN * 2
+        || !part_digest.bytes().all(|x| x.is_ascii_hexdigit())
+        || fields.next().is_some()
+    {
+        return None;
+    }
+    Some((skip, (take, part_digest.to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(data: &[u8]) -> String {
+        blake3::hash(data).to_hex().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_journal_header() {
+        let temp = tempfile::tempdir().unwrap();
+        let target = temp.path().join("a.bin").to_str().unwrap().to_string();
+        let mut journal = Journal::open(&target, "d1", 300, 100).await.unwrap();
+        assert!(journal.is_empty());
+        journal.append(0, 100, &hash(b"part")).await.unwrap();
+        drop(journal);
+        assert!(Journal::resumable(&target, "d1", 300, 100).await);
+        //another version or layout of the file starts over
+        for (digest, size, part_size) in [("d2", 300, 100), ("d1", 301, 100), ("d1", 300, 150)] {
+            assert!(!Journal::resumable(&target, digest, size, part_size).await);
+        }
+        let journal = Journal::open(&target, "d1", 300, 150).await.unwrap();
+        assert!(journal.is_empty());
+        drop(journal);
+        assert_eq!(
+            std::fs::read_to_string(target.clone() + JOURNAL_FILE_EXT).unwrap(),
+            "d1,300,150"
+        );
+        let journal = Journal::open(&target, "d1", 300, 150).await.unwrap();
+        journal.remove().await.unwrap();
+        assert!(!temp.path().join("a.bin.filer-journal").exists());
+    }
+
+    #[tokio::test]
+    async fn test_journal_truncated_line() {
+        let temp = tempfile::tempdir().unwrap();
+        let target = temp.path().join("a.bin").to_str().unwrap().to_string();
+        let mut journal = Journal::open(&target, "d1", 300, 100).await.unwrap();
+        journal.append(0, 100, &hash(b"0")).await.unwrap();
+        journal.append(100, 100, &hash(b"1")).await.unwrap();
+        drop(journal);
+        //a crash while the last line was written
+        let journal_file_name = target.clone() + JOURNAL_FILE_EXT;
+        let content = std::fs::read_to_string(&journal_file_name).unwrap();
+        std::fs::write(&journal_file_name, &content[..content.len() - 10]).unwrap();
+        let mut journal = Journal::open(&target, "d1", 300, 100).await.unwrap();
+        assert_eq!(journal.part(0), Some(&(100, hash(b"0"))));
+        assert_eq!(journal.part(100), None);
+        //a line of the right length left zero-filled
+        let zeros = format!("{}200,100,{}", EOL, "\0".repeat(blake3::OUT_LEN * 2));
+        journal.file.write_all(zeros.as_bytes()).await.unwrap();
+        journal.file.flush().await.unwrap();
+        drop(journal);
+        let mut journal = Journal::open(&target, "d1", 300, 100).await.unwrap();
+        assert_eq!(journal.part(200), None);
+        //lines appended later are read after the broken one
+        journal.append(100, 100, &hash(b"1")).await.unwrap();
+        drop(journal);
+        let journal = Journal::open(&target, "d1", 300, 100).await.unwrap();
+        assert_eq!(journal.part(100), Some(&(100, hash(b"1"))));
+    }
+
+    #[tokio::test]
+    async fn test_part_on_disk() {
+        let temp = tempfile::tempdir().unwrap();
+        let file_name = temp.path().join("a.bin").to_str().unwrap().to_string();
+        let data = [[1u8; 100], [2u8; 100], [3u8; 100]].concat();
+        std::fs::write(&file_name, &data).unwrap();
+        let parts: Vec<String> = data.chunks(100).map(hash).collect();
+        assert!(part_on_disk(&file_name, 0, 100, &parts[0]).await);
+        assert!(part_on_disk(&file_name, 200, 100, &parts[2]).await);
+        //a part changed on disk since it was journaled is fetched again, the others are kept
+        let mut changed = data.clone();
+        changed[150] = 0;
+        std::fs::write(&file_name, &changed).unwrap();
+        assert!(part_on_disk(&file_name, 0, 100, &parts[0]).await);
+        assert!(!part_on_disk(&file_name, 100, 100, &parts[1]).await);
+        assert!(part_on_disk(&file_name, 200, 100, &parts[2]).await);
+        //a part cut short
+        std::fs::write(&file_name, &data[..250]).unwrap();
+        assert!(!part_on_disk(&file_name, 200, 100, &parts[2]).await);
+    }
+}
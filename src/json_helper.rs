@@ -1,4 +1,5 @@
 use serde_json::Value;
+#[allow(dead_code)]
 pub trait JsonHelper {
     //fn str(&self, default: &str) -> &str;
     fn str(&self, default: &'static str) -> &str;
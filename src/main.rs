@@ -1,18 +1,42 @@
 mod config;
 mod context;
+#[cfg(any(feature = "index", feature = "download", feature = "server"))]
+mod filelist;
 mod fileutil;
 mod json_helper;
 mod log;
 
 #[cfg(feature = "server")]
 mod api;
+#[cfg(feature = "server")]
+mod auth;
+#[cfg(any(feature = "server", feature = "download"))]
+mod bandwidth;
+#[cfg(feature = "server")]
+mod catalog;
 #[cfg(any(feature = "server", feature = "download"))]
 mod base16;
+#[cfg(any(feature = "index", feature = "download"))]
+mod chunk;
+#[cfg(feature = "download")]
+mod concurrency;
 #[cfg(feature = "download")]
 mod download;
+#[cfg(feature = "download")]
+mod journal;
+#[cfg(any(feature = "download", feature = "xcopy"))]
+mod plan;
 
+#[cfg(feature = "server")]
+mod shutdown;
 #[cfg(feature = "server")]
 mod static_files;
+#[cfg(feature = "server")]
+mod tls;
+#[cfg(all(feature = "server", unix))]
+mod unix_socket;
+#[cfg(all(feature = "server", feature = "index"))]
+mod watch;
 
 #[cfg(any(feature = "server", feature = "download"))]
 mod addr;
@@ -32,6 +56,8 @@ use std::path::PathBuf;
 use tokio::time::Instant;
 use tracing::debug;
 
+#[cfg(feature = "index")]
+use filelist::LIST_NAME;
 #[cfg(feature = "index")]
 use fileutil::refresh_dir_files_digest;
 
@@ -60,6 +86,15 @@ async fn main() -> Result<()> {
 
     debug!("catalog = {:#?}", catalog);
 
+    #[cfg(any(feature = "download", feature = "xcopy"))]
+    let dry_run = if !args.get_flag("dry-run") {
+        None
+    } else if args.get_flag("json") {
+        Some(plan::PlanFormat::Json)
+    } else {
+        Some(plan::PlanFormat::Text)
+    };
+
     #[cfg(feature = "index")]
     if args.get_flag("index") || get_flag_repeat {
         let config = context.config[catalog].clone();
@@ -67,6 +102,7 @@ async fn main() -> Result<()> {
             println!("catalog {} not found in config", catalog);
         } else {
             let part_size = config["part_size"].u64(102400u64);
+            let delta_file_size = config["delta_file_size"].u64(16 * 1024 * 1024);
             let max_tasks = config["max_tasks"].u64(cpus * 2);
             let path = config["path"].str("");
             if path.is_empty() {
@@ -74,10 +110,14 @@ async fn main() -> Result<()> {
             } else {
                 refresh_dir_files_digest(
                     path,
-                    "filelist.txt",
+                    catalog,
+                    LIST_NAME,
                     part_size,
+                    delta_file_size,
                     max_tasks,
                     get_flag_repeat,
+                    args.get_flag("full"),
+                    true,
                 )
                 .await?;
             }
@@ -97,12 +137,19 @@ async fn main() -> Result<()> {
         if source_path.is_empty() || target_path.is_empty() {
             println!("Usage: filer --xcopy source_path target_path")
         } else {
-            xcopy::xcopy_files(&config, source_path, target_path, cpus * 2).await?;
+            xcopy::xcopy_files(&config, source_path, target_path, cpus * 2, dry_run).await?;
         }
     }
+    let mut exit_code = 0;
     if args.get_flag("server") {
         #[cfg(feature = "server")]
-        server(&context).await;
+        {
+            #[cfg(feature = "index")]
+            let watch_all = args.get_flag("watch");
+            #[cfg(not(feature = "index"))]
+            let watch_all = false;
+            exit_code = server(&context, watch_all, cpus).await;
+        }
         #[cfg(not(feature = "server"))]
         println!("run as server not suported");
     } else if args.get_flag("download") || args.get_flag("update") {
@@ -112,11 +159,18 @@ async fn main() -> Result<()> {
             args.get_flag("download"),
             cpus * 4,
             catalog,
+            args.get_flag("prune"),
+            args.get_flag("prune-dry-run"),
+            dry_run,
         )
         .await?;
         #[cfg(not(feature = "download"))]
         println!("download/update not suported");
     }
+    #[cfg(any(feature = "download", feature = "xcopy"))]
+    if dry_run == Some(plan::PlanFormat::Json) {
+        return Ok(());
+    }
     let pcpus = num_cpus::get_physical() as u64;
     println!(
         "Time taken: {}\nNumber of CPU cores: {}x{}",
@@ -124,51 +178,230 @@ async fn main() -> Result<()> {
         pcpus,
         cpus / pcpus
     );
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
     Ok(())
 }
 
 #[cfg(feature = "server")]
-async fn server(context: &Arc<AppContext>) {
+async fn server(context: &Arc<AppContext>, watch_all: bool, cpus: u64) -> i32 {
     let server_config = context.config["server"].clone();
+    //a config written before server.catalogs existed would serve nothing, refuse to start
+    let catalogs = config::served_catalog_names(&context.config);
+    for name in server_config["catalogs"].as_array().into_iter().flatten() {
+        if !name.as_str().is_some_and(|x| catalogs.iter().any(|catalog| catalog == x)) {
+            tracing::error!("server.catalogs: {} is not a catalog with a path in config", name);
+        }
+    }
+    if catalogs.is_empty() {
+        tracing::error!(
+            "No catalog is served, list the catalogs to serve in server.catalogs of config, like \"catalogs\": [\"demo\"]"
+        );
+        return 1;
+    }
+
+    #[cfg(feature = "index")]
+    watch::watch_catalogs(&context.config, watch_all, cpus, LIST_NAME);
+    #[cfg(not(feature = "index"))]
+    let _ = (watch_all, cpus);
 
     let static_path = server_config["static_path"].string("public");
     let cache_age_in_minute: i32 = server_config["static_cache_age_in_minute"].i64(30) as i32;
 
+    let shutdown = shutdown::Shutdown::new();
     let ctx = context.clone();
     let app = Router::new()
         .nest("/api", api::api(ctx))
-        .fallback_service(static_files::make_service(static_path, cache_age_in_minute));
+        .fallback_service(static_files::make_service(static_path, cache_age_in_minute))
+        .layer(axum::middleware::from_fn_with_state(
+            shutdown.clone(),
+            shutdown::track,
+        ));
 
-    let http_server = tokio::spawn(start_server(server_config.clone(), false, app.clone()));
-    let https_server = tokio::spawn(start_server(server_config, true, app));
-    let (_, _) = tokio::join!(http_server, https_server);
+    let http_server = tokio::spawn(start_server(
+        server_config.clone(),
+        false,
+        app.clone(),
+        shutdown.clone(),
+    ));
+    let https_server = tokio::spawn(start_server(
+        server_config.clone(),
+        true,
+        app.clone(),
+        shutdown.clone(),
+    ));
+    let unix_server = tokio::spawn(start_unix_server(
+        server_config.clone(),
+        app,
+        shutdown.clone(),
+    ));
+    let servers = async { tokio::join!(http_server, https_server, unix_server) };
+    tokio::pin!(servers);
+    //on SIGINT/SIGTERM stop accepting and let transfers in flight finish within
+    //"shutdown_grace_period_in_second" (default 30), exit code is 1 when some had to be aborted
+    //listeners run until shutdown, all of them done before a signal means none could start
+    let signal = tokio::select! {
+        _ = &mut servers => {
+            tracing::error!("No server is listening, check http, https and unix_socket in config");
+            return 1;
+        }
+        signal = shutdown::signal() => signal,
+    };
+    let grace = server_config["shutdown_grace_period_in_second"].u64(30);
+    println!(
+        "{} received, stop accepting connections, wait up to {}s for requests in flight",
+        signal, grace
+    );
+    let (drained, aborted) = shutdown.drain(std::time::Duration::from_secs(grace)).await;
+    let _ = servers.await;
+    println!(
+        "Server stopped, {} requests drained, {} requests aborted",
+        drained, aborted
+    );
+    if aborted > 0 {
+        1
+    } else {
+        0
+    }
 }
 
+//"unix_socket": "/run/filer/filer.sock" listens on a unix domain socket too, for a reverse proxy
+//on the same host, "unix_socket_mode" is the octal file mode of the socket, default 660
 #[cfg(feature = "server")]
-async fn start_server(config: Value, is_https: bool, app: Router) {
-    use axum_server::tls_rustls::RustlsConfig;
+async fn start_unix_server(config: Value, app: Router, shutdown: shutdown::Shutdown) {
+    let path = config["unix_socket"].str("");
+    if path.is_empty() {
+        return;
+    }
+    let server_name = config["server_name"].string("W3");
+    #[cfg(unix)]
+    {
+        use axum::extract::ConnectInfo;
+        use axum::Extension;
+        use std::net::SocketAddr;
+        let listener = match unix_socket::bind(path, config["unix_socket_mode"].str("660")) {
+            Ok(listener) => listener,
+            Err(e) => {
+                println!("{} unix socket server can not start: {}", server_name, e);
+                return;
+            }
+        };
+        let now = &chrono::Local::now().to_string()[0..19];
+        println!(
+            "{} unix socket server version {} started at {} listening on {}",
+            server_name, VERSION, now, path
+        );
+        //peers on a unix socket have no ip address, they get a marker only "unix" in allow_ips
+        //or trusted_proxies matches, a trusted proxy passes the client in X-Forwarded-For
+        let peer = SocketAddr::new(auth::UNIX_PEER, 0);
+        let app = app.layer(Extension(ConnectInfo(peer)));
+        let server = axum::Server::builder(unix_socket::UnixAccept(listener))
+            .serve(app.into_make_service())
+            .with_graceful_shutdown(shutdown.wait(shutdown::Phase::Draining));
+        tokio::select! {
+            result = server => if let Err(e) = result {
+                println!("{} unix socket server stopped: {}", server_name, e);
+            },
+            _ = shutdown.wait(shutdown::Phase::Closing) => {},
+        }
+        let _ = std::fs::remove_file(path);
+    }
+    #[cfg(not(unix))]
+    let _ = (app, shutdown);
+    #[cfg(not(unix))]
+    println!(
+        "{} unix socket server can not start: unix_socket {} is not supported on this platform",
+        server_name, path
+    );
+}
+
+#[cfg(feature = "server")]
+async fn start_server(config: Value, is_https: bool, app: Router, shutdown: shutdown::Shutdown) {
+    use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
     use chrono::Local;
     use std::net::SocketAddr;
     let server_name = config["server_name"].string("W3");
     let protocol = if is_https { "HTTPS" } else { "HTTP" };
-    let config_addr = addr::Addr::new(&config, is_https);
-    let (is_active, addr) = config_addr.get();
-    if is_active {
+    let config_addr = match addr::Addr::new(&config, is_https) {
+        Ok(config_addr) => config_addr,
+        Err(e) => {
+            println!("{} {} server can not start: {}", server_name, protocol, e);
+            return;
+        }
+    };
+    if config_addr.is_active() {
+        let tls_config = if is_https {
+            let cert_file_name = config["https_cert"].string("server.cer");
+            let key_file_name = config["https_key"].string("server.key");
+            match tls::server_config(&config, &cert_file_name, &key_file_name) {
+                Ok(tls_config) => {
+                    let tls_config = RustlsConfig::from_config(Arc::new(tls_config));
+                    tokio::spawn(tls::reload_on_change(
+                        config.clone(),
+                        tls_config.clone(),
+                        cert_file_name,
+                        key_file_name,
+                    ));
+                    Some(tls_config)
+                }
+                Err(e) => {
+                    println!(
+                        "{} {} server can not start, check https_cert and https_key in config: {}",
+                        server_name, protocol, e
+                    );
+                    return;
+                }
+            }
+        } else {
+            None
+        };
+        let listeners = match config_addr.listen() {
+            Ok(listeners) => listeners,
+            Err(e) => {
+                println!("{} {} server can not start: {}", server_name, protocol, e);
+                return;
+            }
+        };
         let now = &Local::now().to_string()[0..19];
         println!(
             "{} {} server version {} started at {} listening on {}",
             server_name, protocol, VERSION, now, &config_addr
         );
         let app = app.into_make_service_with_connect_info::<SocketAddr>();
-        let server = if is_https {
-            let tls_config = RustlsConfig::from_pem_file("server.cer", "server.key")
-                .await
-                .unwrap();
-            axum_server::bind_rustls(addr, tls_config).serve(app).await
-        } else {
-            axum_server::bind(addr).serve(app).await
-        };
-        server.unwrap();
+        let servers = listeners.into_iter().map(|listener| {
+            let app = app.clone();
+            let tls_config = tls_config.clone();
+            let shutdown = shutdown.clone();
+            async move {
+                let addr = listener.local_addr()?;
+                let handle = axum_server::Handle::new();
+                tokio::spawn({
+                    let handle = handle.clone();
+                    async move {
+                        shutdown.wait(shutdown::Phase::Draining).await;
+                        handle.graceful_shutdown(None);
+                        shutdown.wait(shutdown::Phase::Closing).await;
+                        handle.shutdown();
+                    }
+                });
+                let server = axum_server::from_tcp(listener).handle(handle);
+                if let Some(tls_config) = tls_config {
+                    server
+                        .acceptor(tls::ClientCertAcceptor::new(RustlsAcceptor::new(tls_config)))
+                        .serve(app)
+                        .await
+                } else {
+                    server.serve(app).await
+                }
+                .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", addr, e)))
+            }
+        });
+        for result in futures_util::future::join_all(servers).await {
+            if let Err(e) = result {
+                println!("{} {} server stopped: {}", server_name, protocol, e);
+            }
+        }
     } else {
         println!(
             "{} {} server version {} is not active !",
@@ -191,7 +424,8 @@ fn args() -> ArgMatches {
     #[cfg(feature = "index")]
     let app = app
         .arg(arg!(-i --index "Generate the filelist.txt which contains a list of file hash,size,name").action(ArgAction::SetTrue))
-        .arg(arg!(-r --repeat "List repeated files while indexing").action(ArgAction::SetTrue));
+        .arg(arg!(-r --repeat "List repeated files while indexing").action(ArgAction::SetTrue))
+        .arg(arg!(--full "Recalculate digest of every file instead of reusing digest of unchanged files while indexing").action(ArgAction::SetTrue));
 
     #[cfg(feature = "xcopy")]
     let app = app
@@ -213,6 +447,13 @@ fn args() -> ArgMatches {
             .conflicts_with("update"),
     );
 
+    #[cfg(all(feature = "server", feature = "index"))]
+    let app = app.arg(
+        arg!(-w --watch "Re-index every catalog automatically when its files change, use with -s")
+            .action(ArgAction::SetTrue)
+            .requires("server"),
+    );
+
     #[cfg(feature = "download")]
     let app = app
         .arg(
@@ -225,6 +466,25 @@ fn args() -> ArgMatches {
             arg!(-u --update "Run as file update client")
                 .conflicts_with("server")
                 .conflicts_with("download"),
+        )
+        .arg(
+            arg!(--prune "Delete local files which were removed from the server catalog")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"prune-dry-run" "Show local files which --prune would delete")
+                .action(ArgAction::SetTrue),
+        );
+    #[cfg(any(feature = "download", feature = "xcopy"))]
+    let app = app
+        .arg(
+            arg!(--"dry-run" "Show what download/update/xcopy would do without changing anything")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--json "Print the --dry-run plan as JSON")
+                .action(ArgAction::SetTrue)
+                .requires("dry-run"),
         );
     app.get_matches()
 }
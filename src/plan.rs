@@ -0,0 +1,198 @@
+use byte_unit::Byte;
+use serde_json::{json, Value};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlanFormat {
+    Text,
+    Json,
+}
+
+//What download/update/xcopy would do, printed by --dry-run instead of doing it
+pub(crate) struct Plan {
+    source: String,
+    target: String,
+    kill: Vec<String>,
+    //(file_name,size)
+    download: Vec<(String, u64)>,
+    //(file_name,size,fetch_size) of the files rebuilt from local chunks
+    delta: Vec<(String, u64, u64)>,
+    //(file_name,size,source_file_name)
+    copy: Vec<(String, u64, String)>,
+    //(file_name,size,source_file_name)
+    rename: Vec<(String, u64, String)>,
+    prune: Vec<String>,
+}
+
+impl Plan {
+    pub fn new(source: &str, target: &str) -> Self {
+        Plan {
+            source: source.to_string(),
+            target: target.to_string(),
+            kill: Vec::new(),
+            download: Vec::new(),
+            delta: Vec::new(),
+            copy: Vec::new(),
+            rename: Vec::new(),
+            prune: Vec::new(),
+        }
+    }
+    pub fn kill(&mut self, image_name: &str) {
+        self.kill.push(image_name.to_string());
+    }
+    pub fn download(&mut self, file_name: &str, size: u64) {
+        self.download.push((file_name.to_string(), size));
+    }
+    pub fn delta(&mut self, file_name: &str, size: u64, fetch_size: u64) {
+        self.delta.push((file_name.to_string(), size, fetch_size));
+    }
+    pub fn copy(&mut self, file_name: &str, size: u64, source_file_name: &str) {
+        self.copy
+            .push((file_name.to_string(), size, source_file_name.to_string()));
+    }
+    pub fn rename(&mut self, file_name: &str, size: u64, source_file_name: &str) {
+        self.rename
+            .push((file_name.to_string(), size, source_file_name.to_string()));
+    }
+    pub fn prune(&mut self, file_name: &str) {
+        self.prune.push(file_name.to_string());
+    }
+    pub fn download_size(&self) -> u64 {
+        self.download.iter().map(|x| x.1).sum()
+    }
+    //only the chunks not found locally are fetched
+    pub fn delta_size(&self) -> u64 {
+        self.delta.iter().map(|x| x.2).sum()
+    }
+    pub fn copy_size(&self) -> u64 {
+        self.copy.iter().map(|x| x.1).sum()
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "dry_run": true,
+            "source": self.source,
+            "target": self.target,
+            "kill": self.kill,
+            "download": self.download.iter().map(|(file, size)| {
+                json!({"file": file, "size": size})
+            }).collect::<Vec<Value>>(),
+            "delta": self.delta.iter().map(|(file, size, fetch_size)| {
+                json!({"file": file, "size": size, "fetch_size": fetch_size})
+            }).collect::<Vec<Value>>(),
+            "copy": self.copy.iter().map(|(file, size, source)| {
+                json!({"file": file, "size": size, "source": source})
+            }).collect::<Vec<Value>>(),
+            "rename": self.rename.iter().map(|(file, size, source)| {
+                json!({"file": file, "size": size, "source": source})
+            }).collect::<Vec<Value>>(),
+            "prune": self.prune,
+            "download_size": self.download_size(),
+            "delta_size": self.delta_size(),
+            "copy_size": self.copy_size(),
+        })
+    }
+
+    pub fn print(&self, format: PlanFormat) {
+        if format == PlanFormat::Json {
+            println!("{:#}", self.to_json());
+            return;
+        }
+        println!(
+            "Dry run from {} to {}, nothing is changed:",
+            self.source, self.target
+        );
+        if !self.kill.is_empty() {
+            println!("Kill running exe: {}", self.kill.join(".."));
+        }
+        let mut print_count: usize = 0;
+        for (file_name, size) in &self.download {
+            print_count += 1;
+            println!(">>{: ^#4} {} {} ...download", print_count, file_name, size);
+        }
+        for (file_name, size, fetch_size) in &self.delta {
+            print_count += 1;
+            println!(
+                ">>{: ^#4} {} {} ...delta sync fetching {}",
+                print_count, file_name, size, fetch_size
+            );
+        }
+        for (file_name, size, source_file_name) in &self.copy {
+            print_count += 1;
+            println!(
+                ">>{: ^#4} {} {} ...copy from {}",
+                print_count, file_name, size, source_file_name
+            );
+        }
+        for (file_name, size, source_file_name) in &self.rename {
+            print_count += 1;
+            println!(
+                ">>{: ^#4} {} {} ...rename from {}",
+                print_count, file_name, size, source_file_name
+            );
+        }
+        for (i, file_name) in self.prune.iter().enumerate() {
+            println!("<<{: ^#4} {} ...prune", i + 1, file_name);
+        }
+        println!(
+            "Download {} files with size {}, delta sync {} files fetching {}, copy {} files with size {}, rename {} files, prune {} files.",
+            self.download.len(),
+            Byte::from_bytes(self.download_size() as u128).get_appropriate_unit(false),
+            self.delta.len(),
+            Byte::from_bytes(self.delta_size() as u128).get_appropriate_unit(false),
+            self.copy.len(),
+            Byte::from_bytes(self.copy_size() as u128).get_appropriate_unit(false),
+            self.rename.len(),
+            self.prune.len()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_plan() {
+        let mut plan = Plan::new("demo", "/data");
+        plan.kill("demo.exe");
+        plan.download("a.bin", 10);
+        plan.download("dir/b.bin", 5);
+        plan.delta("c.bin", 1000, 40);
+        plan.copy("d.bin", 20, "a.bin");
+        let json = plan.to_json();
+        assert_eq!(json["dry_run"], true);
+        assert_eq!(json["source"], "demo");
+        assert_eq!(json["target"], "/data");
+        assert_eq!(json["kill"], json!(["demo.exe"]));
+        assert_eq!(
+            json["download"],
+            json!([{"file": "a.bin", "size": 10}, {"file": "dir/b.bin", "size": 5}])
+        );
+        assert_eq!(
+            json["delta"],
+            json!([{"file": "c.bin", "size": 1000, "fetch_size": 40}])
+        );
+        //a delta synced file counts only the chunks it fetches, not its size
+        assert_eq!(json["download_size"], 15);
+        assert_eq!(json["delta_size"], 40);
+        assert_eq!(json["copy_size"], 20);
+        assert_eq!(json["rename"], json!([]));
+        assert_eq!(json["prune"], json!([]));
+    }
+    #[test]
+    fn test_plan_rename() {
+        let mut plan = Plan::new("demo", "/data");
+        plan.download("a.bin", 10);
+        plan.copy("b.bin", 20, "a.bin");
+        plan.rename("new/c.bin", 30, "c.bin");
+        plan.prune("d.bin");
+        let json = plan.to_json();
+        assert_eq!(
+            json["rename"],
+            json!([{"file": "new/c.bin", "size": 30, "source": "c.bin"}])
+        );
+        //a rename moves no bytes, it counts neither as download nor as copy
+        assert_eq!(json["download_size"], 10);
+        assert_eq!(json["copy_size"], 20);
+        assert_eq!(json["prune"], json!(["d.bin"]));
+    }
+}
@@ -0,0 +1,179 @@
+use axum::{
+    body::{boxed, HttpBody},
+    extract::State,
+    http::Request,
+    middleware::Next,
+    response::Response,
+};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub(crate) enum Phase {
+    Running,
+    //stop accepting connections, requests in flight go on
+    Draining,
+    //close whatever is still open
+    Closing,
+}
+
+//requests in flight and the shutdown phase shared by every listener
+#[derive(Clone)]
+pub(crate) struct Shutdown {
+    in_flight: Arc<AtomicUsize>,
+    completed: Arc<AtomicUsize>,
+    phase: Arc<watch::Sender<Phase>>,
+}
+
+struct InFlight(Shutdown);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+        self.0.completed.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self {
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            completed: Arc::new(AtomicUsize::new(0)),
+            phase: Arc::new(watch::channel(Phase::Running).0),
+        }
+    }
+
+    pub async fn wait(&self, phase: Phase) {
+        let mut receiver = self.phase.subscribe();
+        while *receiver.borrow_and_update() < phase {
+            if receiver.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    //stop accepting, wait up to grace for requests in flight, then close the rest,
+    //return (drained,aborted) requests
+    pub async fn drain(&self, grace: Duration) -> (usize, usize) {
+        let completed = self.completed.load(Ordering::SeqCst);
+        self.phase.send_replace(Phase::Draining);
+        let deadline = tokio::time::Instant::now() + grace;
+        while self.in_flight.load(Ordering::SeqCst) > 0 && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let aborted = self.in_flight.load(Ordering::SeqCst);
+        let drained = self.completed.load(Ordering::SeqCst) - completed;
+        self.phase.send_replace(Phase::Closing);
+        (drained, aborted)
+    }
+}
+
+//count a request in flight until its response body is finished or dropped
+pub(crate) async fn track<B>(
+    State(shutdown): State<Shutdown>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    shutdown.in_flight.fetch_add(1, Ordering::SeqCst);
+    let in_flight = InFlight(shutdown);
+    next.run(request).await.map(|body| {
+        boxed(body.map_data(move |data| {
+            let _ = &in_flight;
+            data
+        }))
+    })
+}
+
+//SIGINT or SIGTERM, only Ctrl-C where there are no unix signals
+pub(crate) async fn signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => "SIGINT",
+                _ = terminate.recv() => "SIGTERM",
+            },
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+                "SIGINT"
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl-C"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn app(shutdown: &Shutdown) -> Router {
+        Router::new().route("/", get(|| async { "done" })).layer(
+            axum::middleware::from_fn_with_state(shutdown.clone(), track),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_track() {
+        let shutdown = Shutdown::new();
+        let response = app(&shutdown)
+            .oneshot(Request::new(Body::empty()))
+            .await
+            .unwrap();
+        //a request is in flight until its response body is done
+        assert_eq!(shutdown.in_flight.load(Ordering::SeqCst), 1);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"done");
+        assert_eq!(shutdown.in_flight.load(Ordering::SeqCst), 0);
+        assert_eq!(shutdown.completed.load(Ordering::SeqCst), 1);
+        //a body dropped before it is read counts as done too
+        let response = app(&shutdown)
+            .oneshot(Request::new(Body::empty()))
+            .await
+            .unwrap();
+        drop(response);
+        assert_eq!(shutdown.in_flight.load(Ordering::SeqCst), 0);
+        assert_eq!(shutdown.completed.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let shutdown = Shutdown::new();
+        let draining = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.wait(Phase::Draining).await }
+        });
+        let response = app(&shutdown)
+            .oneshot(Request::new(Body::empty()))
+            .await
+            .unwrap();
+        //a response still open when grace is over is aborted
+        let start = tokio::time::Instant::now();
+        assert_eq!(shutdown.drain(Duration::from_millis(300)).await, (0, 1));
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert_eq!(*shutdown.phase.borrow(), Phase::Closing);
+        draining.await.unwrap();
+        drop(response);
+        //a response done within grace is drained without waiting for the rest of it
+        let shutdown = Shutdown::new();
+        let response = app(&shutdown)
+            .oneshot(Request::new(Body::empty()))
+            .await
+            .unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            drop(response);
+        });
+        let start = tokio::time::Instant::now();
+        assert_eq!(shutdown.drain(Duration::from_secs(30)).await, (1, 0));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
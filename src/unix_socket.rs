@@ -0,0 +1,61 @@
+use anyhow::{anyhow, Result};
+use hyper::server::accept::Accept;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::net::{UnixListener, UnixStream};
+
+//bind a unix domain socket, a stale socket left by a previous run is removed first,
+//mode is octal like "660"
+pub(crate) fn bind(path: &str, mode: &str) -> Result<UnixListener> {
+    let mode = parse_mode(mode)?;
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(anyhow!("{} exists and is not a socket", path));
+        }
+        std::fs::remove_file(path).map_err(|e| anyhow!("remove stale {} fail: {}", path, e))?;
+    }
+    let listener =
+        UnixListener::bind(path).map_err(|e| anyhow!("listen on {} fail: {}", path, e))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .map_err(|e| anyhow!("set mode of {} fail: {}", path, e))?;
+    Ok(listener)
+}
+
+fn parse_mode(mode: &str) -> Result<u32> {
+    match u32::from_str_radix(mode.trim(), 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(anyhow!(
+            "unix_socket_mode {} is not an octal mode like 660",
+            mode
+        )),
+    }
+}
+
+pub(crate) struct UnixAccept(pub UnixListener);
+
+impl Accept for UnixAccept {
+    type Conn = UnixStream;
+    type Error = std::io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.0
+            .poll_accept(cx)
+            .map(|x| Some(x.map(|(stream, _)| stream)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("660").unwrap(), 0o660);
+        assert_eq!(parse_mode("0600").unwrap(), 0o600);
+        assert!(parse_mode("8").is_err());
+        assert!(parse_mode("1777").is_err());
+    }
+}
@@ -0,0 +1,158 @@
+use crate::config::catalog_names;
+use crate::filelist::{CHUNK_LIST_NAME, PART_LIST_NAME};
+use crate::fileutil::{get_dir_snapshot, refresh_dir_files_digest, DirSnapshot};
+use crate::JsonHelper;
+use anyhow::Result;
+use serde_json::Value;
+use std::time::Duration;
+use tokio::{task, time};
+use tracing::{error, info};
+
+//spawn one watcher per catalog with "watch": true, or every catalog if watch_all,
+//list_name is the file list indexing writes in the root of a catalog
+pub(crate) fn watch_catalogs(config: &Value, watch_all: bool, cpus: u64, list_name: &str) {
+    for catalog in catalog_names(config) {
+        let catalog_config = config[&catalog].clone();
+        if watch_all || catalog_config["watch"].bool(false) {
+            task::spawn(watch_catalog(
+                catalog,
+                catalog_config,
+                cpus,
+                list_name.to_string(),
+            ));
+        }
+    }
+}
+
+async fn watch_catalog(catalog: String, config: Value, cpus: u64, list_name: String) {
+    let path = config["path"].string("");
+    let part_size = config["part_size"].u64(102400u64);
+    let delta_file_size = config["delta_file_size"].u64(16 * 1024 * 1024);
+    let max_tasks = config["max_tasks"].u64(cpus * 2);
+    let interval = Duration::from_secs(config["watch_interval_in_second"].u64(10).max(1));
+    let snapshot = || catalog_snapshot(&path, &list_name);
+    let refresh = || async {
+        match refresh_dir_files_digest(
+            &path,
+            &catalog,
+            &list_name,
+            part_size,
+            delta_file_size,
+            max_tasks,
+            false,
+            false,
+            false,
+        )
+        .await
+        {
+            Ok(_) => info!("catalog {} re-indexed", catalog),
+            Err(e) => error!("catalog {} re-index fail: {:?}", catalog, e),
+        }
+    };
+    info!(
+        "watch catalog {} in {}, interval {:?}",
+        catalog, path, interval
+    );
+    //catch up with changes made while the server was down
+    let mut indexed = snapshot().await.unwrap_or_default();
+    refresh().await;
+    let mut last: Option<DirSnapshot> = None;
+    loop {
+        time::sleep(interval).await;
+        let current = match snapshot().await {
+            Ok(current) => current,
+            Err(e) => {
+                error!("watch catalog {} fail: {:?}", catalog, e);
+                continue;
+            }
+        };
+        if let Some(current) = settled(&indexed, &mut last, current) {
+            refresh().await;
+            indexed = current;
+        }
+    }
+}
+
+//files of a catalog without the lists indexing writes, so indexing triggers no re-index
+async fn catalog_snapshot(path: &str, list_name: &str) -> Result<DirSnapshot> {
+    let mut snapshot = get_dir_snapshot(path).await?;
+    for name in [list_name, PART_LIST_NAME, CHUNK_LIST_NAME] {
+        snapshot.remove(&(String::from(path) + "/" + name));
+    }
+    Ok(snapshot)
+}
+
+//return the snapshot to re-index once it differs from the indexed one and did not change
+//during the last interval, last keeps the snapshot of the previous interval
+fn settled(
+    indexed: &DirSnapshot,
+    last: &mut Option<DirSnapshot>,
+    current: DirSnapshot,
+) -> Option<DirSnapshot> {
+    if &current == indexed {
+        *last = None;
+        None
+    } else if last.as_ref() == Some(&current) {
+        *last = None;
+        Some(current)
+    } else {
+        *last = Some(current);
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filelist::LIST_NAME;
+
+    #[tokio::test]
+    async fn test_catalog_snapshot() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let path = dir.to_str().unwrap();
+        std::fs::write(dir.join("a.txt"), b"a").unwrap();
+        let indexed = catalog_snapshot(path, LIST_NAME).await.unwrap();
+        assert_eq!(indexed.len(), 1);
+        //the lists indexing writes and temporary files are no change
+        for name in [
+            LIST_NAME,
+            PART_LIST_NAME,
+            CHUNK_LIST_NAME,
+            "b.txt.filer-tmp",
+        ] {
+            std::fs::write(dir.join(name), b"list").unwrap();
+        }
+        assert_eq!(catalog_snapshot(path, LIST_NAME).await.unwrap(), indexed);
+        std::fs::write(dir.join("a.txt"), b"aa").unwrap();
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub/c.txt"), b"c").unwrap();
+        let changed = catalog_snapshot(path, LIST_NAME).await.unwrap();
+        assert_eq!(changed.len(), 2);
+        assert_eq!(changed[&(path.to_string() + "/a.txt")].0, 2);
+        std::fs::remove_file(dir.join("a.txt")).unwrap();
+        let removed = catalog_snapshot(path, LIST_NAME).await.unwrap();
+        assert_eq!(
+            removed.keys().collect::<Vec<_>>(),
+            vec![&(path.to_string() + "/sub/c.txt")]
+        );
+    }
+
+    #[test]
+    fn test_settled() {
+        let snapshot = |size: u64| DirSnapshot::from([("/c/a.txt".to_string(), (size, 1))]);
+        let indexed = snapshot(1);
+        let mut last = None;
+        assert_eq!(settled(&indexed, &mut last, snapshot(1)), None);
+        assert_eq!(last, None);
+        //a change is indexed once it stays for a whole interval
+        assert_eq!(settled(&indexed, &mut last, snapshot(2)), None);
+        assert_eq!(settled(&indexed, &mut last, snapshot(3)), None);
+        assert_eq!(settled(&indexed, &mut last, snapshot(3)), Some(snapshot(3)));
+        assert_eq!(last, None);
+        //a change undone before it settled is no change
+        assert_eq!(settled(&indexed, &mut last, snapshot(4)), None);
+        assert_eq!(settled(&indexed, &mut last, snapshot(1)), None);
+        assert_eq!(last, None);
+    }
+}
@@ -1,18 +1,43 @@
 use crate::fileutil::{
-    calc_parts, get_dir_file_size, get_file_size, get_full_of_file, get_part_of_file,
-    kill_running_exe, PartData, MAX_SPLIT_PARTS,
+    calc_parts, copy_and_hash_part_of_file, get_dir_file_size, get_file_size, hash_part_of_file,
+    kill_running_exe, link_file, replace_file, schedule, LinkMode, MAX_SPLIT_PARTS, TEMP_FILE_EXT,
 };
 use crate::json_helper::JsonHelper;
+use crate::plan::{Plan, PlanFormat};
 use anyhow::{anyhow, Result};
 use byte_unit::Byte;
 use serde_json::Value;
-use std::io::SeekFrom;
 use std::path::Path;
+use std::sync::Arc;
 use tokio::fs::{self, DirBuilder, File};
-use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Semaphore;
 use tokio::task;
 
-type XCopyReturn=(String, u64, u64, u64);
+//copy a part into the temporary file, then read it back and compare its blake3
+//with the bytes read from the source, return the size of the part
+async fn copy_part_checked(
+    source_file_name: &str,
+    temp_file_name: &str,
+    skip: u64,
+    take: u64,
+) -> Result<u64> {
+    let (skip, take, digest) =
+        copy_and_hash_part_of_file(source_file_name, temp_file_name, skip, take).await?;
+    let (_, copied_digest) = hash_part_of_file(temp_file_name, skip, take).await?;
+    if copied_digest != digest {
+        return Err(anyhow!(
+            "{} part at {} digest check error, expect: {}, got: {}",
+            source_file_name,
+            skip,
+            digest,
+            copied_digest
+        ));
+    }
+    Ok(take)
+}
+
+//return (file_name,file_size,parts,part_size,linked)
+type XCopyReturn = (String, u64, u64, u64, bool);
 
 async fn xcopy_file(
     source_path: &str,
@@ -20,6 +45,8 @@ async fn xcopy_file(
     file_name: &str,
     file_size: u64,
     part_size: u64,
+    link_mode: LinkMode,
+    slots: Arc<Semaphore>,
 ) -> Result<XCopyReturn> {
     let source_file_name = source_path.to_string() + "/" + file_name;
     let target_file_name = if file_name.to_lowercase().ends_with("filer.exe") {
@@ -36,52 +63,76 @@ async fn xcopy_file(
         .recursive(true)
         .create(target_file_folder)
         .await?;
-    let mut target = File::create(&target_file_name).await?;
-    let mut file_size_calc: u64 = 0;
-    async fn process_result(
-        result: Result<PartData>,
-        target: &mut File,
-        file_size: &mut u64,
-    ) -> Result<()> {
-        let (skip, take, result) = result?;
-        target.seek(SeekFrom::Start(skip)).await?;
-        target.write_all(&result).await?;
-        *file_size += take;
-        Ok(())
+    //the bytes are copied where the file system can not link them, a link replaces the old
+    //target only when it has the size of the source too
+    if link_file(&source_file_name, &target_file_name, file_size, link_mode).await {
+        return Ok((String::from(file_name), file_size, 1, file_size, true));
     }
-    if parts == 1 {
-        let result = get_full_of_file(&source_file_name).await;
-        process_result(result, &mut target, &mut file_size_calc).await?;
-    } else {
-        let mut results: Vec<task::JoinHandle<Result<PartData>>> =
-            Vec::with_capacity(parts as usize);
-        for i in 0..parts as usize {
-            let skip = i as u64 * part_size;
-            let take = part_size;
-            let source_file_name = source_file_name.clone();
-            results.push(task::spawn(async move {
-                get_part_of_file(&source_file_name, skip, take).await
-            }));
+    //the old target stays intact until the copy passes the size and digest checks
+    let temp_file_name = target_file_name.clone() + TEMP_FILE_EXT;
+    let result: Result<u64> = async {
+        File::create(&temp_file_name).await?;
+        let mut file_size_calc: u64 = 0;
+        if parts == 1 {
+            let _permit = slots.acquire().await?;
+            file_size_calc += copy_part_checked(&source_file_name, &temp_file_name, 0, 0).await?;
+        } else {
+            //every part streams into the temporary file through its own handle
+            let mut results: Vec<task::JoinHandle<Result<u64>>> =
+                Vec::with_capacity(parts as usize);
+            for i in 0..parts as usize {
+                let skip = i as u64 * part_size;
+                let take = part_size;
+                let source_file_name = source_file_name.clone();
+                let temp_file_name = temp_file_name.clone();
+                let slots = slots.clone();
+                results.push(task::spawn(async move {
+                    let _permit = slots.acquire_owned().await?;
+                    copy_part_checked(&source_file_name, &temp_file_name, skip, take).await
+                }));
+            }
+            for result in results {
+                file_size_calc += result.await??;
+            }
         }
-        for result in results {
-            let result = result.await?;
-            process_result(result, &mut target, &mut file_size_calc).await?;
+        if file_size_calc != file_size {
+            Err(anyhow!(
+                "{} file size check error, expect: {}, got: {}",
+                file_name,
+                file_size,
+                file_size_calc
+            ))
+        } else {
+            Ok(file_size_calc)
         }
     }
+    .await;
 
-    if file_size_calc != file_size {
-        Err(anyhow!(
-            "{} file size check error, expect: {}, got: {}",
-            file_name,
-            file_size,
-            file_size_calc
-        ))
-    } else {
-        Ok((String::from(file_name), file_size_calc, parts, part_size))
+    match result {
+        Ok(file_size_calc) => {
+            replace_file(&temp_file_name, &target_file_name).await?;
+            Ok((
+                String::from(file_name),
+                file_size_calc,
+                parts,
+                part_size,
+                false,
+            ))
+        }
+        Err(e) => {
+            fs::remove_file(&temp_file_name).await.ok();
+            Err(e)
+        }
     }
 }
 
-pub async fn xcopy_files(config: &Value, source_path: &str, target_path: &str,max_tasks:u64) -> Result<()> {
+pub async fn xcopy_files(
+    config: &Value,
+    source_path: &str,
+    target_path: &str,
+    max_tasks: u64,
+    dry_run: Option<PlanFormat>,
+) -> Result<()> {
     fn fine_path(path: &str) -> Result<String> {
         let path = path.to_string().replace('\\', "/");
         let path: String = if path.ends_with('/') {
@@ -103,6 +154,7 @@ pub async fn xcopy_files(config: &Value, source_path: &str, target_path: &str,ma
     let kill_running = client_config["kill_running_exe"].bool(false);
     let part_size = client_config["part_size"].u64(1024 * 1024);
     let max_tasks = client_config["max_tasks"].u64(max_tasks);
+    let link_mode = LinkMode::new(client_config["link_mode"].str("copy"));
     let meta = fs::metadata(source_path).await?;
     let (source_file_list, source_path, source_path_is_file) = if meta.is_dir() {
         (
@@ -133,14 +185,35 @@ pub async fn xcopy_files(config: &Value, source_path: &str, target_path: &str,ma
     let file_count = source_file_list.len();
     let file_size = source_file_list.iter().map(|x| x.1).sum::<u64>();
 
-    if kill_running {
-        let exe_list = source_file_list
+    let exe_list = if kill_running {
+        source_file_list
             .iter()
             .map(|x| Path::new(&x.0))
             .filter(|x| x.extension().unwrap_or_else(||OsStr::new("")) == "exe")
             .map(|x| x.file_name().unwrap().to_str().unwrap())
             .filter(|x| x.to_lowercase() != "filer.exe")
-            .collect::<HashSet<&str>>();
+            .collect::<HashSet<&str>>()
+    } else {
+        HashSet::new()
+    };
+
+    if let Some(format) = dry_run {
+        let mut plan = Plan::new(&source_path, target_path);
+        let mut exe_list = exe_list.into_iter().collect::<Vec<&str>>();
+        exe_list.sort();
+        exe_list.iter().for_each(|x| plan.kill(x));
+        for (file_name, file_size) in source_file_list.iter() {
+            plan.copy(
+                file_name,
+                *file_size,
+                &(source_path.clone() + "/" + file_name),
+            );
+        }
+        plan.print(format);
+        return Ok(());
+    }
+
+    if kill_running {
         if !exe_list.is_empty() {
             print!("Kill running exe: ");
             for image_name in exe_list {
@@ -155,7 +228,6 @@ pub async fn xcopy_files(config: &Value, source_path: &str, target_path: &str,ma
     }
 
     let mut xcopy_error_count: usize = 0;
-    let mut i: usize = 0;
     let mut print_count: usize = 0;
 
     if source_path_is_file {
@@ -163,43 +235,54 @@ pub async fn xcopy_files(config: &Value, source_path: &str, target_path: &str,ma
     } else {
         println!("XCopy files from {}/ to {}/ ...", source_path, target_path);
     }
-    while i < file_count {
-        let mut task_count = 0u64;
-        let mut results: Vec<task::JoinHandle<Result<XCopyReturn>>> =
-            Vec::with_capacity(max_tasks as usize);
-        while task_count < max_tasks && i < file_count {
-            let (file_name, file_size) = source_file_list
-                .get(i)
-                .ok_or_else(||anyhow!("xcopy source_file_list.get() error"))?;
-            let file_size = *file_size;
-            let (task_add, part_size) = calc_parts(file_size, part_size, MAX_SPLIT_PARTS);
-            let file_name: String = file_name.into();
-            let source_path = source_path.to_string();
-            let target_path = target_path.to_string();
-            task_count += task_add;
-            results.push(task::spawn(async move {
-                xcopy_file(&source_path, &target_path, &file_name, file_size, part_size).await
-            }));
-            i += 1;
+    //max_tasks files at once, each part of them takes a slot shared by all files,
+    //the next file starts as soon as one is done
+    let slots = Arc::new(Semaphore::new(max_tasks.max(1) as usize));
+    let jobs = source_file_list.into_iter().map({
+        let source_path = source_path.to_string();
+        let target_path = target_path.to_string();
+        move |(file_name, file_size)| {
+            let source_path = source_path.clone();
+            let target_path = target_path.clone();
+            let slots = slots.clone();
+            async move {
+                xcopy_file(
+                    &source_path,
+                    &target_path,
+                    &file_name,
+                    file_size,
+                    part_size,
+                    link_mode,
+                    slots,
+                )
+                .await
+            }
         }
-        for result in results {
-            print_count += 1;
-            let result = result.await?;
-            match result {
-                Ok((file_name, file_size, parts, _part_size)) => {
-                    println!(
-                        ">>{: ^#4} {} {}={} pack{} ...",
-                        print_count,
-                        file_name,
-                        file_size,
-                        parts,
-                        if parts > 1 { "s" } else { "" }
-                    );
-                }
-                Err(e) => {
-                    xcopy_error_count += 1;
-                    println!(">>{: ^#4} {:?}", print_count, e);
-                }
+    });
+    let mut results = schedule(max_tasks, jobs);
+    while let Some(result) = results.recv().await {
+        print_count += 1;
+        let result = result?;
+        match result {
+            Ok((file_name, file_size, _parts, _part_size, true)) => {
+                println!(
+                    ">>{: ^#4} {} {} ...linked",
+                    print_count, file_name, file_size
+                );
+            }
+            Ok((file_name, file_size, parts, _part_size, false)) => {
+                println!(
+                    ">>{: ^#4} {} {}={} pack{} ...",
+                    print_count,
+                    file_name,
+                    file_size,
+                    parts,
+                    if parts > 1 { "s" } else { "" }
+                );
+            }
+            Err(e) => {
+                xcopy_error_count += 1;
+                println!(">>{: ^#4} {:?}", print_count, e);
             }
         }
     }